@@ -0,0 +1,80 @@
+//! Lookups over a large synthetic buffer, comparing the line index against
+//! rescanning the text for newlines. Run them with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use rust_ed::{Buffer, Session};
+
+const LINES: usize = 200_000;
+const LOOKUPS: usize = 1_000;
+
+fn main() {
+    line_to_offset();
+    edit_then_lookup();
+}
+
+fn line_to_offset() {
+    let text = synthetic_text();
+    let buf = Buffer::with_contents(&text);
+    let lines = sample(LINES);
+
+    let indexed = time(|| lines.iter().map(|n| buf.lines_from(*n).next().map_or(0, |(_, l)| l.len())).sum());
+    let scanned = time(|| lines.iter().map(|n| line_len(&text, scan_line_start(&text, *n))).sum());
+    report("line to offset", indexed, scanned);
+}
+
+fn edit_then_lookup() {
+    let mut text = synthetic_text();
+    let mut session = Session::with_contents(&text);
+    let lines = sample(LINES);
+
+    let indexed = time(|| {
+        lines.iter().map(|n| {
+            session.run_with_input(&format!("{}i", n), "x\n.\n").unwrap();
+            session.buffer().lines_from(*n + 1).next().map_or(0, |(_, l)| l.len())
+        }).sum()
+    });
+    let scanned = time(|| {
+        lines.iter().map(|n| {
+            let p = scan_line_start(&text, *n);
+            text.insert_str(p, "x\n");
+            line_len(&text, scan_line_start(&text, *n + 1))
+        }).sum()
+    });
+    report("insert and lookup", indexed, scanned);
+}
+
+fn synthetic_text() -> String {
+    (0..LINES).map(|i| format!("synthetic line {i} with some padding text\n")).collect()
+}
+
+fn sample(max: usize) -> Vec<usize> {
+    let mut seed: usize = 42;
+    (0..LOOKUPS).map(|_| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) % max + 1
+    }).collect()
+}
+
+fn scan_line_start(text: &str, n: usize) -> usize {
+    text.match_indices('\n').nth(n - 2).map(|(p, _)| p + 1).unwrap_or(0)
+}
+
+fn line_len(text: &str, start: usize) -> usize {
+    text[start..].find('\n').unwrap_or(text.len() - start)
+}
+
+fn time(f: impl FnOnce() -> usize) -> Duration {
+    let start = Instant::now();
+    std::hint::black_box(f());
+    start.elapsed()
+}
+
+fn report(name: &str, indexed: Duration, scanned: Duration) {
+    println!(
+        "{name}: {LOOKUPS} ops, indexed {:?}, scanned {:?} ({:.0}x)",
+        indexed,
+        scanned,
+        scanned.as_secs_f64() / indexed.as_secs_f64()
+    );
+}
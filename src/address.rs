@@ -0,0 +1,378 @@
+use crate::editor::{CommandError, Editor, ParseError, RuntimeError};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Address {
+    Current,
+    Last,
+    Line(usize),
+    Offset(Box<Address>, isize),
+    Forward(String),
+    Backward(String),
+    Mark(char),
+}
+
+impl Address {
+    pub fn resolve(&self, ed: &mut Editor) -> Result<usize, CommandError> {
+        self.resolve_from(ed, ed.buffer.line)
+    }
+
+    fn resolve_from(&self, ed: &mut Editor, current: usize) -> Result<usize, CommandError> {
+        let current = ed.buffer.clamp_line(current);
+        let line = match self {
+            Self::Current => current,
+            Self::Last => ed.buffer.line_count(),
+            Self::Line(n) => *n,
+            Self::Offset(base, n) => {
+                let base = isize::try_from(base.resolve_from(ed, current)?).map_err(|_| ParseError::AddressOutOfRange)?;
+                base.checked_add(*n)
+                    .and_then(|line| usize::try_from(line).ok())
+                    .ok_or(ParseError::AddressOutOfRange)?
+            },
+            Self::Forward(pattern) => search_forward(ed, pattern, current)?,
+            Self::Backward(pattern) => search_backward(ed, pattern, current)?,
+            Self::Mark(c) => ed.buffer.mark(*c).ok_or(ParseError::InvalidAddress)?,
+        };
+
+        if !ed.buffer.has_line(line) {
+            Err(CommandError::Parse(ParseError::AddressOutOfRange))
+        } else {
+            Ok(line)
+        }
+    }
+}
+
+/// First line after `current` matching `pattern`, wrapping around to the
+/// start of the buffer.
+fn search_forward(ed: &mut Editor, pattern: &str, current: usize) -> Result<usize, CommandError> {
+    let re = ed.search_pattern(pattern)?;
+    ed.buffer.lines_from(current + 1)
+        .chain(ed.buffer.lines_from(1).take(current))
+        .find(|(_, line)| re.is_match(line))
+        .map(|(n, _)| n)
+        .ok_or(CommandError::Runtime(RuntimeError::NoMatch))
+}
+
+/// Nearest line before `current` matching `pattern`, wrapping around to the
+/// end of the buffer. Lines only stream forwards, so this keeps the last
+/// match on either side of `current`.
+fn search_backward(ed: &mut Editor, pattern: &str, current: usize) -> Result<usize, CommandError> {
+    let re = ed.search_pattern(pattern)?;
+    let (mut before, mut after) = (None, None);
+    for (n, _) in ed.buffer.lines().filter(|(_, line)| re.is_match(line)) {
+        if n < current {
+            before = Some(n);
+        } else {
+            after = Some(n);
+        }
+    }
+    before.or(after).ok_or(CommandError::Runtime(RuntimeError::NoMatch))
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum Range {
+    #[default]
+    Implicit,
+    Single(Address),
+    Pair(Address, Address),
+    Chained(Address, Address),
+}
+
+impl Range {
+    pub fn resolve(&self, ed: &mut Editor) -> Result<Option<(usize, usize)>, CommandError> {
+        let (start, end) = match self {
+            Self::Implicit => return Ok(None),
+            Self::Single(a) => {
+                let line = a.resolve(ed)?;
+                (line, line)
+            },
+            Self::Pair(a, b) => (a.resolve(ed)?, b.resolve(ed)?),
+            Self::Chained(a, b) => {
+                let start = a.resolve(ed)?;
+                (start, b.resolve_from(ed, start)?)
+            },
+        };
+
+        if start > end {
+            Err(CommandError::Parse(ParseError::InvalidAddress))
+        } else {
+            Ok(Some((start, end)))
+        }
+    }
+
+    pub fn lines_or_current(&self, ed: &mut Editor) -> Result<(usize, usize), CommandError> {
+        match self.resolve(ed)? {
+            Some(lines) => Ok(lines),
+            None => {
+                let line = Address::Current.resolve(ed)?;
+                Ok((line, line))
+            }
+        }
+    }
+
+    pub fn line_or_current(&self, ed: &mut Editor) -> Result<usize, CommandError> {
+        self.lines_or_current(ed).map(|(_, end)| end)
+    }
+}
+
+pub fn parse_range(s: &str) -> Result<(Range, &str), CommandError> {
+    if let Some(rest) = s.strip_prefix('%') {
+        return Ok((Range::Pair(Address::Line(1), Address::Last), rest));
+    }
+
+    let (first, mut rest) = parse_address(s)?;
+    let mut range = first.clone().map(Range::Single).unwrap_or_default();
+    let mut prev = first;
+
+    while let Some(sep) = rest.chars().next().filter(|c| *c == ',' || *c == ';') {
+        let (next, r) = parse_address(&rest[1..])?;
+        rest = r;
+
+        let (start, end) = match (prev, next, sep) {
+            (None, None, ',') => (Address::Line(1), Address::Last),
+            (None, None, _) => (Address::Current, Address::Last),
+            (None, Some(b), ',') => (Address::Line(1), b),
+            (None, Some(b), _) => (Address::Current, b),
+            (Some(a), None, _) => (a.clone(), a),
+            (Some(a), Some(b), _) => (a, b),
+        };
+
+        prev = Some(end.clone());
+        range = if sep == ',' {
+            Range::Pair(start, end)
+        } else {
+            Range::Chained(start, end)
+        };
+    }
+
+    Ok((range, rest))
+}
+
+fn parse_address(s: &str) -> Result<(Option<Address>, &str), CommandError> {
+    let (base, mut rest) = match s.chars().next() {
+        Some('.') => (Some(Address::Current), &s[1..]),
+        Some('$') => (Some(Address::Last), &s[1..]),
+        Some('/') => {
+            let (pattern, rest) = split_delimited(&s[1..], '/');
+            (Some(Address::Forward(pattern)), rest)
+        },
+        Some('?') => {
+            let (pattern, rest) = split_delimited(&s[1..], '?');
+            (Some(Address::Backward(pattern)), rest)
+        },
+        Some(c) if c.is_ascii_digit() => {
+            let (n, rest) = parse_number(s)?;
+            (Some(Address::Line(n)), rest)
+        },
+        Some('\'') => match s[1..].chars().next() {
+            Some(c) if c.is_ascii_lowercase() => (Some(Address::Mark(c)), &s[2..]),
+            _ => return Err(CommandError::Parse(ParseError::InvalidAddress)),
+        },
+        _ => (None, s),
+    };
+
+    let mut offset: Option<isize> = None;
+    while let Some(c @ ('+' | '-')) = rest.chars().next() {
+        let (n, r) = if rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            parse_number(&rest[1..])?
+        } else {
+            (1, &rest[1..])
+        };
+        rest = r;
+
+        let n = isize::try_from(n).map_err(|_| ParseError::InvalidAddress)?;
+        let n = if c == '-' { -n } else { n };
+        offset = Some(offset.unwrap_or(0).checked_add(n).ok_or(ParseError::InvalidAddress)?);
+    }
+
+    let addr = match (base, offset) {
+        (base, Some(n)) => Some(Address::Offset(Box::new(base.unwrap_or(Address::Current)), n)),
+        (base, None) => base,
+    };
+
+    Ok((addr, rest))
+}
+
+/// Splits `s` at the first unescaped `delim`, unescaping the delimiter in
+/// the first part. A missing closing delimiter ends the text at end of line.
+pub fn split_delimited(s: &str, delim: char) -> (String, &str) {
+    let mut text = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delim {
+            return (text, &s[i + c.len_utf8()..]);
+        } else if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delim => text.push(next),
+                Some((_, next)) => {
+                    text.push(c);
+                    text.push(next);
+                },
+                None => text.push(c),
+            }
+        } else {
+            text.push(c);
+        }
+    }
+
+    (text, "")
+}
+
+fn parse_number(s: &str) -> Result<(usize, &str), CommandError> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n = s[..end].parse::<usize>().map_err(|_| ParseError::InvalidAddress)?;
+    Ok((n, &s[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer::Buffer;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_single_address() {
+        assert_eq!(parse("1"), Range::Single(Address::Line(1)));
+        assert_eq!(parse("+"), Range::Single(offset(1)));
+        assert_eq!(parse("+3"), Range::Single(offset(3)));
+        assert_eq!(parse("-"), Range::Single(offset(-1)));
+        assert_eq!(parse("-3"), Range::Single(offset(-3)));
+    }
+
+    #[test]
+    fn test_parse_special_address() {
+        assert_eq!(parse("."), Range::Single(Address::Current));
+        assert_eq!(parse("$"), Range::Single(Address::Last));
+        assert_eq!(parse("$-3"), Range::Single(Address::Offset(Box::new(Address::Last), -3)));
+        assert_eq!(parse("$--"), Range::Single(Address::Offset(Box::new(Address::Last), -2)));
+        assert_eq!(parse(".+2+2"), Range::Single(offset(4)));
+        assert_eq!(parse(".-1+5"), Range::Single(offset(4)));
+        assert_eq!(parse("3+"), Range::Single(Address::Offset(Box::new(Address::Line(3)), 1)));
+        assert_eq!(parse(".-2,.+2"), Range::Pair(offset(-2), offset(2)));
+        assert_eq!(parse("'a,'b"), Range::Pair(Address::Mark('a'), Address::Mark('b')));
+        assert_eq!(parse("'a+1"), Range::Single(Address::Offset(Box::new(Address::Mark('a')), 1)));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse("1,5"), Range::Pair(Address::Line(1), Address::Line(5)));
+        assert_eq!(parse(","), Range::Pair(Address::Line(1), Address::Last));
+        assert_eq!(parse("%"), Range::Pair(Address::Line(1), Address::Last));
+        assert_eq!(parse(";"), Range::Chained(Address::Current, Address::Last));
+        assert_eq!(parse(",3"), Range::Pair(Address::Line(1), Address::Line(3)));
+        assert_eq!(parse("3,"), Range::Pair(Address::Line(3), Address::Line(3)));
+        assert_eq!(parse("2;+3"), Range::Chained(Address::Line(2), offset(3)));
+        assert_eq!(parse("1,2,3"), Range::Pair(Address::Line(2), Address::Line(3)));
+    }
+
+    #[test]
+    fn test_parse_leaves_command() {
+        let (range, rest) = parse_range("1,5p").unwrap();
+        assert_eq!(range, Range::Pair(Address::Line(1), Address::Line(5)));
+        assert_eq!(rest, "p");
+
+        let (range, rest) = parse_range("l").unwrap();
+        assert_eq!(range, Range::Implicit);
+        assert_eq!(rest, "l");
+
+        let (range, rest) = parse_range("$p").unwrap();
+        assert_eq!(range, Range::Single(Address::Last));
+        assert_eq!(rest, "p");
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        let mut ed = editor("1\n2\n3\n4\n5\n");
+        ed.buffer.line = 2;
+
+        assert_eq!(parse(",").resolve(&mut ed).unwrap(), Some((1, 5)));
+        assert_eq!(parse("2;+2").resolve(&mut ed).unwrap(), Some((2, 4)));
+        assert_eq!(parse("-,+").resolve(&mut ed).unwrap(), Some((1, 3)));
+        assert_eq!(parse("$-3").resolve(&mut ed).unwrap(), Some((2, 2)));
+        assert_eq!(parse(".;$").resolve(&mut ed).unwrap(), Some((2, 5)));
+        assert_eq!(parse("").resolve(&mut ed).unwrap(), None);
+        assert_eq!(parse("").lines_or_current(&mut ed).unwrap(), (2, 2));
+    }
+
+    #[test]
+    fn test_resolve_out_of_range() {
+        let mut ed = editor("1\n2\n3\n");
+
+        assert!(matches!(parse("4").resolve(&mut ed), Err(CommandError::Parse(ParseError::AddressOutOfRange))));
+        assert!(matches!(parse("-5").resolve(&mut ed), Err(CommandError::Parse(ParseError::AddressOutOfRange))));
+        assert!(matches!(parse("3,1").resolve(&mut ed), Err(CommandError::Parse(ParseError::InvalidAddress))));
+    }
+
+    #[test]
+    fn test_offset_overflow() {
+        let mut ed = editor("1\n2\n3\n");
+
+        assert!(matches!(parse_range("1+9223372036854775807+5p"), Err(CommandError::Parse(ParseError::InvalidAddress))));
+        assert!(matches!(parse_range("1+99999999999999999999p"), Err(CommandError::Parse(ParseError::InvalidAddress))));
+        assert!(matches!(parse("$+9223372036854775807").resolve(&mut ed), Err(CommandError::Parse(ParseError::AddressOutOfRange))));
+        assert!(matches!(parse("-9223372036854775807-").resolve(&mut ed), Err(CommandError::Parse(ParseError::AddressOutOfRange))));
+    }
+
+    #[test]
+    fn test_parse_search() {
+        assert_eq!(parse("/abc/"), Range::Single(Address::Forward(String::from("abc"))));
+        assert_eq!(parse("?abc?"), Range::Single(Address::Backward(String::from("abc"))));
+        assert_eq!(parse("/a\\/b"), Range::Single(Address::Forward(String::from("a/b"))));
+        assert_eq!(parse("/a,b/+1"), Range::Single(Address::Offset(Box::new(Address::Forward(String::from("a,b"))), 1)));
+        assert_eq!(
+            parse("/begin/,/end/"),
+            Range::Pair(Address::Forward(String::from("begin")), Address::Forward(String::from("end")))
+        );
+
+        let (range, rest) = parse_range("/x/d").unwrap();
+        assert_eq!(range, Range::Single(Address::Forward(String::from("x"))));
+        assert_eq!(rest, "d");
+    }
+
+    #[test]
+    fn test_resolve_mark() {
+        let mut ed = editor("a\nb\nc\n");
+        ed.buffer.set_mark('x', 2);
+
+        assert_eq!(parse("'x,$").resolve(&mut ed).unwrap(), Some((2, 3)));
+        assert!(matches!(parse("'y").resolve(&mut ed), Err(CommandError::Parse(ParseError::InvalidAddress))));
+        assert!(matches!(parse_range("'Ap"), Err(CommandError::Parse(ParseError::InvalidAddress))));
+    }
+
+    #[test]
+    fn test_resolve_search() {
+        let mut ed = editor("foo\nbar\nfoo\nbaz\n");
+        ed.buffer.line = 1;
+
+        assert_eq!(parse("/foo/").resolve(&mut ed).unwrap(), Some((3, 3)));
+        assert_eq!(parse("?ba?").resolve(&mut ed).unwrap(), Some((4, 4)));
+        assert_eq!(parse("/ba/,/baz/").resolve(&mut ed).unwrap(), Some((2, 4)));
+
+        ed.buffer.line = 3;
+        assert_eq!(parse("/foo/").resolve(&mut ed).unwrap(), Some((1, 1)));
+        assert_eq!(parse("//").resolve(&mut ed).unwrap(), Some((1, 1)));
+        assert_eq!(parse("??").resolve(&mut ed).unwrap(), Some((1, 1)));
+    }
+
+    #[test]
+    fn test_search_errors() {
+        let mut ed = editor("foo\n");
+
+        assert!(matches!(parse("//").resolve(&mut ed), Err(CommandError::Runtime(RuntimeError::NoPreviousPattern))));
+        assert!(matches!(parse("/bar/").resolve(&mut ed), Err(CommandError::Runtime(RuntimeError::NoMatch))));
+        assert!(matches!(parse("/(/").resolve(&mut ed), Err(CommandError::Parse(ParseError::InvalidPattern))));
+    }
+
+    fn editor(s: &str) -> Editor {
+        Editor { buffer: Buffer::with_contents(s), ..Default::default() }
+    }
+
+    fn parse(s: &str) -> Range {
+        let (range, rest) = parse_range(s).unwrap();
+        assert!(rest.is_empty());
+        range
+    }
+
+    fn offset(n: isize) -> Address {
+        Address::Offset(Box::new(Address::Current), n)
+    }
+}
@@ -0,0 +1,253 @@
+/// Lines that follow edits, kept in a treap ordered by line. An edit shifts
+/// every anchor below it with one pending update on a subtree instead of
+/// touching each of them, so it costs O(log n) however many anchors exist.
+/// Nodes live in a vector indexed by anchor id, with parent links so that an
+/// anchor's line can be found without searching.
+pub struct Anchors {
+    nodes: Vec<Node>,
+    root: Link,
+    live: usize,
+    seed: u64,
+}
+
+type Link = Option<usize>;
+
+struct Node {
+    line: usize,
+    /// Shift not yet applied to this node and everything below it.
+    shift: isize,
+    priority: u64,
+    parent: Link,
+    left: Link,
+    right: Link,
+    /// Whether the anchor is in the tree. Anchors on deleted lines are taken
+    /// out for good.
+    attached: bool,
+}
+
+impl Default for Anchors {
+    fn default() -> Self {
+        Self { nodes: Vec::new(), root: None, live: 0, seed: 0x9e3779b97f4a7c15 }
+    }
+}
+
+impl Anchors {
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// Adds an anchor on `line` and returns its id.
+    pub fn add(&mut self, line: usize) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        self.nodes.push(Node { line, shift: 0, priority: self.seed, parent: None, left: None, right: None, attached: false });
+        self.live += 1;
+        let id = self.nodes.len() - 1;
+        self.put(id, line);
+        id
+    }
+
+    /// Current line of anchor `id`, or `None` if its line was deleted.
+    pub fn line(&self, id: usize) -> Option<usize> {
+        let node = self.nodes.get(id).filter(|n| n.attached)?;
+        let mut line = node.line as isize + node.shift;
+        let mut parent = node.parent;
+        while let Some(p) = parent {
+            line += self.nodes[p].shift;
+            parent = self.nodes[p].parent;
+        }
+        Some(line as usize)
+    }
+
+    /// Drops anchor `id`. Ids are reused once every anchor has been removed.
+    pub fn remove(&mut self, id: usize) {
+        if let Some(line) = self.line(id) {
+            let others = self.take(line, line).into_iter().filter(|(other, _)| *other != id).collect::<Vec<_>>();
+            for (other, line) in others {
+                self.put(other, line);
+            }
+            self.live -= 1;
+        }
+        if self.live == 0 {
+            *self = Self { seed: self.seed, ..Default::default() };
+        }
+    }
+
+    /// Takes the anchors on lines `start` to `end` out of the tree, returning
+    /// them with their lines so that they can be `put` back elsewhere.
+    pub fn take(&mut self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let root = self.root.take();
+        let (before, rest) = self.split(root, start);
+        let (taken, after) = self.split(rest, end + 1);
+        let root = self.merge(before, after);
+        self.set_root(root);
+
+        let mut out = Vec::new();
+        self.collect(taken, &mut out);
+        for (id, _) in &out {
+            self.nodes[*id].attached = false;
+        }
+        out
+    }
+
+    /// Puts anchor `id` back on `line`.
+    pub fn put(&mut self, id: usize, line: usize) {
+        let node = &mut self.nodes[id];
+        (node.line, node.shift, node.left, node.right, node.attached) = (line, 0, None, None, true);
+
+        let root = self.root.take();
+        let (before, after) = self.split(root, line);
+        let left = self.merge(before, Some(id));
+        let root = self.merge(left, after);
+        self.set_root(root);
+    }
+
+    /// Follows an edit that replaced lines `first` to `last`: anchors below
+    /// them move by `shift`, and `follow` gives the new line of each anchor
+    /// on the replaced lines, or `None` to drop it. `follow` must not move
+    /// anchors past their neighbours.
+    pub fn update(&mut self, first: usize, last: usize, shift: isize, follow: impl Fn(usize) -> Option<usize>) {
+        let replaced = self.take(first, last);
+        let root = self.root.take();
+        let (before, after) = self.split(root, last + 1);
+        if let Some(after) = after {
+            self.nodes[after].shift += shift;
+        }
+        let root = self.merge(before, after);
+        self.set_root(root);
+
+        for (id, line) in replaced {
+            match follow(line) {
+                Some(line) => self.put(id, line),
+                None => self.live -= 1,
+            }
+        }
+    }
+
+    fn push(&mut self, i: usize) {
+        let shift = std::mem::take(&mut self.nodes[i].shift);
+        if shift == 0 {
+            return;
+        }
+
+        self.nodes[i].line = (self.nodes[i].line as isize + shift) as usize;
+        for child in [self.nodes[i].left, self.nodes[i].right].into_iter().flatten() {
+            self.nodes[child].shift += shift;
+        }
+    }
+
+    fn set_root(&mut self, root: Link) {
+        self.root = root;
+        if let Some(r) = root {
+            self.nodes[r].parent = None;
+        }
+    }
+
+    fn set_left(&mut self, i: usize, child: Link) {
+        self.nodes[i].left = child;
+        if let Some(c) = child {
+            self.nodes[c].parent = Some(i);
+        }
+    }
+
+    fn set_right(&mut self, i: usize, child: Link) {
+        self.nodes[i].right = child;
+        if let Some(c) = child {
+            self.nodes[c].parent = Some(i);
+        }
+    }
+
+    /// Splits the tree so that the left part holds the anchors before `line`.
+    fn split(&mut self, t: Link, line: usize) -> (Link, Link) {
+        let Some(i) = t else {
+            return (None, None);
+        };
+
+        self.push(i);
+        if self.nodes[i].line < line {
+            let (l, r) = self.split(self.nodes[i].right, line);
+            self.set_right(i, l);
+            (Some(i), r)
+        } else {
+            let (l, r) = self.split(self.nodes[i].left, line);
+            self.set_left(i, r);
+            (l, Some(i))
+        }
+    }
+
+    fn merge(&mut self, a: Link, b: Link) -> Link {
+        match (a, b) {
+            (None, b) => b,
+            (a, None) => a,
+            (Some(a), Some(b)) => {
+                if self.nodes[a].priority > self.nodes[b].priority {
+                    self.push(a);
+                    let right = self.merge(self.nodes[a].right, Some(b));
+                    self.set_right(a, right);
+                    Some(a)
+                } else {
+                    self.push(b);
+                    let left = self.merge(Some(a), self.nodes[b].left);
+                    self.set_left(b, left);
+                    Some(b)
+                }
+            },
+        }
+    }
+
+    fn collect(&mut self, t: Link, out: &mut Vec<(usize, usize)>) {
+        if let Some(i) = t {
+            self.push(i);
+            self.collect(self.nodes[i].left, out);
+            out.push((i, self.nodes[i].line));
+            self.collect(self.nodes[i].right, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let mut anchors = Anchors::default();
+        let ids = (1..=5).map(|l| anchors.add(l)).collect::<Vec<_>>();
+
+        // Lines 2 and 3 joined into one, with the anchor on 3 dropped.
+        anchors.update(2, 3, -1, |l| (l == 2).then_some(2));
+        let lines = ids.iter().map(|id| anchors.line(*id)).collect::<Vec<_>>();
+        assert_eq!(lines, vec![Some(1), Some(2), None, Some(3), Some(4)]);
+
+        let moved = anchors.take(1, 1);
+        anchors.put(moved[0].0, 4);
+        assert_eq!(anchors.line(ids[0]), Some(4));
+        assert_eq!(anchors.line(ids[4]), Some(4));
+
+        for id in ids {
+            anchors.remove(id);
+        }
+        assert!(anchors.is_empty());
+        assert!(anchors.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_many_anchors() {
+        const LINES: usize = 100_000;
+        let mut anchors = Anchors::default();
+        let ids = (1..=LINES).map(|l| anchors.add(l)).collect::<Vec<_>>();
+
+        // Deleting every other line, the way `g/re/d` does. Walking every
+        // anchor on each edit would take minutes here.
+        for (i, id) in ids.iter().enumerate().step_by(2) {
+            let line = anchors.line(*id).unwrap();
+            assert_eq!(line, i / 2 + 1);
+            anchors.update(line, line, -1, |_| None);
+        }
+
+        assert_eq!(anchors.line(ids[LINES - 1]), Some(LINES / 2));
+        assert_eq!(anchors.live, LINES / 2);
+    }
+}
@@ -1,124 +1,512 @@
+use std::{borrow::Cow, io};
+
+use crate::{
+    anchors::Anchors,
+    encoding::Encoding,
+    journal::{Edit, Journal, Marks},
+    line_ending::LineEnding,
+    mapped::MappedText,
+    position::{column_at, offset_at, ColumnUnit, Position},
+    rope::Rope,
+};
+
 pub struct Buffer {
-    pub contents: String,
+    storage: Storage,
     pub line: usize,
+    pub modified: bool,
+    pub(crate) line_ending: LineEnding,
+    pub encoding: Encoding,
+    /// The text came without a final newline. One is kept internally so
+    /// every line is terminated, and dropped again when writing.
+    pub missing_newline: bool,
+    anchors: Anchors,
+    marks: Marks,
+    journal: Journal,
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Self {
-            contents: String::new(),
-            line: 1
+            storage: Storage::Rope(Rope::default()),
+            line: 1,
+            modified: false,
+            line_ending: LineEnding::default(),
+            encoding: Encoding::default(),
+            missing_newline: false,
+            anchors: Anchors::default(),
+            marks: Marks::new(),
+            journal: Journal::default(),
         }
     }
 }
 
 impl Buffer {
+    /// Creates a buffer from file contents, remembering their line ending
+    /// style and storing them with `\n` line breaks. A missing final newline
+    /// is added.
     pub fn with_contents(s: &str) -> Self {
+        let line_ending = LineEnding::detect(s);
+        let mut text = line_ending.normalize(s).into_owned();
+        let missing_newline = !text.is_empty() && !text.ends_with('\n');
+        if missing_newline {
+            text.push('\n');
+        }
+
         Self {
-            contents: String::from(s),
+            storage: Storage::Rope(Rope::with_text(&text)),
+            line_ending,
+            missing_newline,
             ..Default::default()
         }
     }
 
-    pub fn current_line(&self) -> Region {
+    /// Creates a buffer from raw file bytes, detecting their encoding unless
+    /// one is given.
+    pub fn decode(bytes: &[u8], encoding: Option<Encoding>) -> Self {
+        let encoding = encoding.unwrap_or_else(|| Encoding::detect(bytes));
+        Self {
+            encoding,
+            ..Self::with_contents(&encoding.decode(bytes))
+        }
+    }
+
+    /// Maps `path` into memory without reading it. The buffer is read-only
+    /// until it is replaced by an editable one.
+    pub fn map_file(path: &str) -> io::Result<Self> {
+        let text = MappedText::open(path)?;
+        let line_ending = text.line_ending();
+        let missing_newline = text.last_char().is_some_and(|c| c != '\n');
+        Ok(Self {
+            storage: Storage::Mapped(text),
+            line_ending,
+            missing_newline,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.storage, Storage::Mapped(_))
+    }
+
+    pub fn text(&self) -> String {
+        self.storage.slice(0, self.storage.len()).into_owned()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn current_line(&self) -> Region {
         self.line_at(self.line)
     }
 
-    pub fn line_at(&self, line: usize) -> Region {
-        if line > 1 {
-            let mut lines = self.contents
-                .char_indices()
-                .filter(|(_, c)| *c == '\n')
-                .map(|(n, _)| n)
-                .skip(line - 2);
+    pub(crate) fn line_at(&self, line: usize) -> Region {
+        let line = self.clamp_line(line).max(1);
+        let start = self.line_start(line);
+        let end = self.storage.nth_newline(line - 1).unwrap_or(self.storage.len());
+        Region(start, end)
+    }
+
+    pub(crate) fn beginning_of_line(&self, n: usize) -> Point {
+        Point(self.line_start(n.max(1)))
+    }
 
-            let start = lines.next().map(|n| n + 1);
-            let end = lines.next().unwrap_or(self.contents.len());
-            let start = start.or_else(|| self.contents[..end-1].rfind('\n')).unwrap_or(0);
-            Region(start, end)
-        } else {
-            let end = self.contents.char_indices()
-                .find(|(_, c)| *c == '\n')
-                .map(|(n, _)| n)
-                .unwrap_or(self.contents.len());
+    pub(crate) fn lines_region(&self, start: usize, end: usize) -> Region {
+        let Region(start, _) = self.line_at(start);
+        let Region(_, end) = self.line_at(end);
+        Region(start, end)
+    }
+
+    /// Region spanning lines `start` to `end`, including the final newline.
+    pub(crate) fn whole_lines(&self, start: usize, end: usize) -> Region {
+        let Region(start, _) = self.line_at(start);
+        let Point(end) = self.end_of_line(end);
+        Region(start, end)
+    }
+
+    pub(crate) fn lines_text(&self, start: usize, end: usize) -> Cow<'_, str> {
+        self.region_text(&self.whole_lines(start, end))
+    }
 
-            Region(0, end)
+    pub(crate) fn end_of_line(&self, n: usize) -> Point {
+        if n == 0 {
+            return Point(0);
         }
+
+        Point(self.line_start(n + 1))
     }
 
-    pub fn beginning_of_line(&self, n: usize) -> Point {
-        if n > 1 {
-            self.contents
-                .char_indices()
-                .filter(|(_, c)| *c == '\n')
-                .map(|(n, _)| n)
-                .nth(n - 2)
-                .map(|n| Point(n + 1))
-                .unwrap_or(Point(0))
+    /// Byte offset where line `n` starts, or the end of the buffer if there
+    /// are fewer lines.
+    fn line_start(&self, n: usize) -> usize {
+        if n <= 1 {
+            0
         } else {
-            Point(0)
+            self.storage.nth_newline(n - 2).map(|p| p + 1).unwrap_or(self.storage.len())
         }
     }
 
-    pub fn end_of_line(&self, n: usize) -> Point {
-        self.contents
-            .char_indices()
-            .filter(|(_, c)| *c == '\n')
-            .map(|(n, _)| n)
-            .nth(n - 1)
-            .map(|n| Point(n + 1))
-            .unwrap_or(Point(0))
+    pub(crate) fn line_at_point(&self, p: &Point) -> usize {
+        self.storage.newlines_before(p.0) + 1
+    }
+
+    #[cfg(test)]
+    pub(crate) fn region_line_number(&self, Region(start, _): &Region) -> usize {
+        self.storage.newlines_before(*start) + 1
+    }
+
+    /// Point at `pos`. The line is clamped like in `line_at`, and the column
+    /// to the end of the line; a column inside a character or grapheme
+    /// cluster moves back to its start.
+    pub(crate) fn point_at(&self, pos: Position, unit: ColumnUnit) -> Point {
+        let Region(start, end) = self.line_at(pos.line);
+        Point(start + offset_at(&self.storage.slice(start, end), pos.column, unit))
     }
 
-    pub fn line_at_point(&self, p: &Point) -> usize {
-        self.contents[..p.0].lines().count() + 1
+    pub(crate) fn position(&self, p: &Point, unit: ColumnUnit) -> Position {
+        let line = self.line_at_point(p);
+        let Region(start, end) = self.line_at(line);
+        let column = column_at(&self.storage.slice(start, end), p.0.saturating_sub(start), unit);
+        Position::new(line, column)
     }
 
-    pub fn region_line_number(&self, Region(start, _): &Region) -> usize {
-        self.contents[..*start].lines().count() + 1
+    /// Region between two positions, in whichever order they are given.
+    pub(crate) fn region_between(&self, a: Position, b: Position, unit: ColumnUnit) -> Region {
+        let Point(a) = self.point_at(a, unit);
+        let Point(b) = self.point_at(b, unit);
+        Region(a.min(b), a.max(b))
     }
 
-    pub fn region_text(&self, r: &Region) -> &str {
+    pub(crate) fn region_text(&self, r: &Region) -> Cow<'_, str> {
         let Region(start, end) = r;
-        &self.contents[*start..*end]
+        self.storage.slice(*start, *end)
+    }
+
+    /// Line `n` cut down to `columns` of display width, and whether
+    /// anything had to be cut off.
+    pub fn fit_line(&self, n: usize, columns: usize) -> (Cow<'_, str>, bool) {
+        let visible = self.region_between(Position::new(n, 0), Position::new(n, columns), ColumnUnit::Width);
+        let end = self.point_at(Position::new(n, usize::MAX), ColumnUnit::Bytes);
+        (self.region_text(&visible), self.position(&end, ColumnUnit::Width).column > columns)
     }
 
-    pub fn insert(&mut self, p: Point, s: &str) -> Point {
-        self.contents.insert_str(p.0, s);
+    pub(crate) fn insert(&mut self, p: Point, s: &str) -> Point {
+        self.splice(p.0, p.0, s);
         Point(p.0 + s.len())
     }
 
-    pub fn replace(&mut self, Region(start, end): Region, s: &str) -> Region {
-        self.contents.replace_range(start..end, s);
-        Region(start, s.len())
+    pub(crate) fn replace(&mut self, Region(start, end): Region, s: &str) -> Region {
+        self.splice(start, end, s);
+        Region(start, start + s.len())
+    }
+
+    fn splice(&mut self, start: usize, end: usize, s: &str) {
+        let edit = Edit {
+            start,
+            removed: self.storage.slice(start, end).into_owned(),
+            inserted: String::from(s),
+        };
+
+        // Edits made outside of a transaction are undone on their own.
+        let standalone = !self.journal.is_open();
+        if standalone {
+            self.begin_change();
+        }
+        self.journal.save_state(&self.marks, self.missing_newline);
+        // Anything replacing or following the unterminated last line ends
+        // with a newline of its own.
+        if end == self.storage.len() {
+            self.missing_newline = false;
+        }
+        self.apply(&edit);
+        self.journal.record(edit);
+        if standalone {
+            self.commit_change();
+        }
+    }
+
+    fn apply(&mut self, edit: &Edit) {
+        let end = edit.start + edit.removed.len();
+        self.move_anchors(edit.start, end, &edit.inserted);
+        self.storage.make_editable().replace(edit.start, end, &edit.inserted);
+        self.modified = true;
+    }
+
+    /// Groups all following edits into a single undoable change, until
+    /// `commit_change` is called.
+    pub(crate) fn begin_change(&mut self) {
+        self.journal.begin(self.line);
+    }
+
+    pub(crate) fn commit_change(&mut self) {
+        self.journal.commit(self.line, self.missing_newline);
+    }
+
+    pub(crate) fn undo(&mut self) -> bool {
+        let Some(t) = self.journal.pop_undo() else {
+            return false;
+        };
+
+        for edit in t.edits.iter().rev() {
+            self.apply(&Edit {
+                start: edit.start,
+                removed: edit.inserted.clone(),
+                inserted: edit.removed.clone(),
+            });
+        }
+        self.line = t.line_before;
+        self.missing_newline = t.missing_newline_before;
+        self.marks = t.marks_before.clone();
+        self.journal.push_redo(t);
+        true
+    }
+
+    pub(crate) fn redo(&mut self) -> bool {
+        let Some(t) = self.journal.pop_redo() else {
+            return false;
+        };
+
+        for edit in t.edits.iter() {
+            self.apply(edit);
+        }
+        self.line = t.line_after;
+        self.missing_newline = t.missing_newline_after;
+        self.journal.push_undo(t);
+        true
+    }
+
+    /// Moves lines `start` to `end` after line `dest`, keeping anchors
+    /// attached to the moved lines. `dest` must be outside of the moved range.
+    pub(crate) fn move_lines(&mut self, start: usize, end: usize, dest: usize) {
+        let mut text = String::from(self.lines_text(start, end));
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+
+        let moved = self.anchors.take(start, end);
+        let moved_marks = self.marks.iter()
+            .filter(|(_, l)| (start..=end).contains(*l))
+            .map(|(c, l)| (*c, l - start))
+            .collect::<Vec<_>>();
+
+        let count = end - start + 1;
+        let new_start = if dest >= end {
+            let p = self.end_of_line(dest);
+            self.insert(p, &text);
+            self.replace(self.whole_lines(start, end), "");
+            dest - count + 1
+        } else {
+            self.replace(self.whole_lines(start, end), "");
+            let p = self.end_of_line(dest);
+            self.insert(p, &text);
+            dest + 1
+        };
+
+        for (id, line) in moved {
+            self.anchors.put(id, new_start + line - start);
+        }
+        for (c, offset) in moved_marks {
+            self.marks.insert(c, new_start + offset);
+        }
+    }
+
+    /// Marks a line with `name`. Like anchors, marks follow their line and
+    /// are cleared when it is deleted; undo restores them.
+    pub(crate) fn set_mark(&mut self, name: char, line: usize) {
+        self.marks.insert(name, line);
+    }
+
+    pub fn mark(&self, name: char) -> Option<usize> {
+        self.marks.get(&name).copied()
+    }
+
+    /// Attaches an anchor to a line. The anchor follows the line as text is
+    /// inserted or removed above it, and is dropped when the line is deleted.
+    pub(crate) fn anchor(&mut self, line: usize) -> Anchor {
+        Anchor(self.anchors.add(line))
+    }
+
+    pub(crate) fn anchor_line(&self, Anchor(id): &Anchor) -> Option<usize> {
+        self.anchors.line(*id)
+    }
+
+    pub(crate) fn release(&mut self, Anchor(id): Anchor) {
+        self.anchors.remove(id);
+    }
+
+    /// Moves anchors and marks for replacing `start..end` with `s`.
+    fn move_anchors(&mut self, start: usize, end: usize, s: &str) {
+        if self.anchors.is_empty() && self.marks.is_empty() {
+            return;
+        }
+
+        let first = self.storage.newlines_before(start) + 1;
+        let last = self.storage.newlines_before(end) + 1;
+        let added = s.matches('\n').count();
+        let prefix_kept = start > self.line_start(first);
+        let follow = |l: usize| match l {
+            l if l < first => Some(l),
+            l if l > last => Some(l + added + first - last),
+            l if prefix_kept => (l == first).then_some(l),
+            l => (l == last).then_some(first + added),
+        };
+
+        self.anchors.update(first, last, (first + added) as isize - last as isize, follow);
+        self.marks.retain(|_, l| match follow(*l) {
+            Some(new) => {
+                *l = new;
+                true
+            },
+            None => false,
+        });
+    }
+
+    /// Whether line `n` exists, with 0 standing for the position before the
+    /// first line. Unlike `line_count`, this only needs a mapped file's
+    /// line index to reach line `n`.
+    pub fn has_line(&self, n: usize) -> bool {
+        n == 0 || self.line_start(n) < self.storage.len()
+    }
+
+    /// `n`, or the last line if there are fewer lines.
+    pub(crate) fn clamp_line(&self, n: usize) -> usize {
+        if self.has_line(n) {
+            n
+        } else {
+            self.line_count()
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        let unterminated = self.storage.last_char().is_some_and(|c| c != '\n');
+        self.storage.newlines() + usize::from(unterminated)
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = (usize, Cow<'_, str>)> {
+        self.lines_from(1)
     }
 
-    pub fn lines(&self) -> impl Iterator<Item = (usize, &str)> {
-        self.contents.lines().enumerate().map(|(n, s)| (n+1, s))
+    /// Iterates over lines starting at line `n`, without scanning the ones
+    /// before it.
+    pub fn lines_from(&self, n: usize) -> impl Iterator<Item = (usize, Cow<'_, str>)> {
+        let n = n.max(1);
+        let start = self.line_start(n);
+        let skip = self.storage.newlines_before(start) - self.storage.newlines_before(self.storage.chunk_start(start));
+        self.storage.chunks_from(start)
+            .flat_map(chunk_lines)
+            .skip(skip)
+            .enumerate()
+            .map(move |(i, s)| (n + i, s))
     }
 
-    pub fn lines_around(&self, l: usize, n: usize) -> impl Iterator<Item = (usize, &str)> {
+    pub fn lines_around(&self, l: usize, n: usize) -> impl Iterator<Item = (usize, Cow<'_, str>)> {
         let (lines_before, lines_after) = split_integer(n);
-        self.lines()
-            .skip(if lines_before > l { 0 } else { l - lines_before - 1 })
+        self.lines_from(if lines_before > l { 1 } else { l - lines_before })
             .take(lines_before + lines_after)
     }
 }
 
+/// Splits a chunk into lines, borrowing them unless the chunk had to be
+/// decoded.
+fn chunk_lines(chunk: Cow<'_, str>) -> Box<dyn Iterator<Item = Cow<'_, str>> + '_> {
+    match chunk {
+        Cow::Borrowed(s) => Box::new(s.lines().map(Cow::Borrowed)),
+        Cow::Owned(s) => Box::new(s.lines().map(|l| Cow::Owned(String::from(l))).collect::<Vec<_>>().into_iter()),
+    }
+}
+
 fn split_integer(n: usize) -> (usize, usize) {
-    if n % 2 == 0 {
+    if n.is_multiple_of(2) {
         (n/2, n/2)
     } else {
         (n/2, n/2 + 1)
     }
 }
 
+/// Buffer text, either held in an editable rope or mapped read-only from a
+/// file.
+enum Storage {
+    Rope(Rope),
+    Mapped(MappedText),
+}
+
+impl Storage {
+    fn len(&self) -> usize {
+        match self {
+            Storage::Rope(r) => r.len(),
+            Storage::Mapped(m) => m.len(),
+        }
+    }
+
+    fn newlines(&self) -> usize {
+        match self {
+            Storage::Rope(r) => r.newlines(),
+            Storage::Mapped(m) => m.newlines(),
+        }
+    }
+
+    fn last_char(&self) -> Option<char> {
+        match self {
+            Storage::Rope(r) => r.last_char(),
+            Storage::Mapped(m) => m.last_char(),
+        }
+    }
+
+    fn newlines_before(&self, at: usize) -> usize {
+        match self {
+            Storage::Rope(r) => r.newlines_before(at),
+            Storage::Mapped(m) => m.newlines_before(at),
+        }
+    }
+
+    fn nth_newline(&self, n: usize) -> Option<usize> {
+        match self {
+            Storage::Rope(r) => r.nth_newline(n),
+            Storage::Mapped(m) => m.nth_newline(n),
+        }
+    }
+
+    fn slice(&self, start: usize, end: usize) -> Cow<'_, str> {
+        match self {
+            Storage::Rope(r) => r.slice(start, end),
+            Storage::Mapped(m) => m.slice(start, end),
+        }
+    }
+
+    fn chunks_from(&self, at: usize) -> Box<dyn Iterator<Item = Cow<'_, str>> + '_> {
+        match self {
+            Storage::Rope(r) => Box::new(r.chunks_from(at).map(Cow::Borrowed)),
+            Storage::Mapped(m) => Box::new(m.chunks_from(at)),
+        }
+    }
+
+    fn chunk_start(&self, at: usize) -> usize {
+        match self {
+            Storage::Rope(r) => r.chunk_start(at),
+            Storage::Mapped(m) => m.chunk_start(at),
+        }
+    }
+
+    /// Copies mapped text into a rope so it can be changed. Commands refuse
+    /// to edit read-only buffers, so this only guards direct buffer use.
+    fn make_editable(&mut self) -> &mut Rope {
+        if let Storage::Mapped(m) = self {
+            *self = Storage::Rope(Rope::with_text(&m.slice(0, m.len())));
+        }
+
+        match self {
+            Storage::Rope(r) => r,
+            Storage::Mapped(_) => unreachable!(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Region(usize, usize);
 
 #[derive(Debug)]
 pub struct Point(usize);
 
+#[derive(Debug)]
+pub struct Anchor(usize);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -143,7 +531,7 @@ mod tests {
 
         let r = buf.replace(buf.current_line(), "changed");
 
-        assert_eq!(buf.contents, "changed\nsecond\nthird\n");
+        assert_eq!(buf.text(), "changed\nsecond\nthird\n");
         assert_eq!(buf.region_text(&r), "changed");
     }
 
@@ -153,12 +541,66 @@ mod tests {
 
         let p = buf.beginning_of_line(2);
         let p = buf.insert(p, "second\n");
-        assert_eq!(buf.contents, "first\nsecond\nthird\n");
+        assert_eq!(buf.text(), "first\nsecond\nthird\n");
         assert_eq!(p.0, 13);
 
         let p = buf.end_of_line(2);
         buf.insert(p, "more\n");
-        assert_eq!(buf.contents, "first\nsecond\nmore\nthird\n");
+        assert_eq!(buf.text(), "first\nsecond\nmore\nthird\n");
+    }
+
+    #[test]
+    fn test_modified() {
+        let mut buf = Buffer::with_contents("first\n");
+        assert!(!buf.modified);
+
+        buf.insert(buf.end_of_line(1), "second\n");
+        assert!(buf.modified);
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut buf = Buffer::with_contents("one\ntwo\n");
+
+        buf.begin_change();
+        buf.replace(buf.line_at(1), "ONE");
+        buf.insert(buf.end_of_line(2), "three\n");
+        buf.line = 3;
+        buf.commit_change();
+
+        buf.replace(buf.line_at(2), "TWO");
+        assert_eq!(buf.text(), "ONE\nTWO\nthree\n");
+
+        assert!(buf.undo());
+        assert_eq!(buf.text(), "ONE\ntwo\nthree\n");
+        assert!(buf.undo());
+        assert_eq!(buf.text(), "one\ntwo\n");
+        assert_eq!(buf.line, 1);
+        assert!(!buf.undo());
+
+        assert!(buf.redo());
+        assert_eq!(buf.text(), "ONE\ntwo\nthree\n");
+        assert_eq!(buf.line, 3);
+        assert!(buf.redo());
+        assert!(!buf.redo());
+        assert_eq!(buf.text(), "ONE\nTWO\nthree\n");
+    }
+
+    #[test]
+    fn test_move_lines() {
+        let mut buf = Buffer::with_contents("1\n2\n3\n4\n5\n");
+        let two = buf.anchor(2);
+        let four = buf.anchor(4);
+
+        buf.move_lines(1, 2, 4);
+        assert_eq!(buf.text(), "3\n4\n1\n2\n5\n");
+        assert_eq!(buf.anchor_line(&two), Some(4));
+        assert_eq!(buf.anchor_line(&four), Some(2));
+
+        buf.move_lines(4, 5, 0);
+        assert_eq!(buf.text(), "2\n5\n3\n4\n1\n");
+        assert_eq!(buf.anchor_line(&two), Some(1));
+        assert_eq!(buf.anchor_line(&four), Some(4));
     }
 
     #[test]
@@ -180,13 +622,156 @@ mod tests {
         assert_eq!(buf.region_line_number(&p), 1);
     }
 
+    #[test]
+    fn test_lines_region() {
+        let buf = Buffer::with_contents("one\ntwo\nthree\nfour\n");
+
+        let r = buf.lines_region(2, 3);
+        assert_eq!(buf.region_text(&r), "two\nthree");
+        assert_eq!(buf.line_count(), 4);
+    }
+
+    #[test]
+    fn test_anchor_follows_edits() {
+        let mut buf = Buffer::with_contents("one\ntwo\nthree\nfour\n");
+        let two = buf.anchor(2);
+        let three = buf.anchor(3);
+        let four = buf.anchor(4);
+
+        buf.insert(buf.beginning_of_line(1), "zero\n");
+        assert_eq!(buf.anchor_line(&two), Some(3));
+
+        let r = buf.line_at(3);
+        buf.replace(r, "TWO");
+        assert_eq!(buf.anchor_line(&two), Some(3));
+
+        buf.replace(buf.whole_lines(4, 4), "");
+        assert_eq!(buf.text(), "zero\none\nTWO\nfour\n");
+        assert_eq!(buf.anchor_line(&two), Some(3));
+        assert_eq!(buf.anchor_line(&three), None);
+        assert_eq!(buf.anchor_line(&four), Some(4));
+
+        buf.release(two);
+        buf.release(three);
+        buf.release(four);
+        assert!(buf.anchors.is_empty());
+    }
+
+    #[test]
+    fn test_marks_follow_edits() {
+        let mut buf = Buffer::with_contents("one\ntwo\nthree\n");
+        buf.set_mark('a', 2);
+        buf.set_mark('b', 3);
+
+        buf.begin_change();
+        buf.insert(buf.beginning_of_line(1), "zero\n");
+        buf.replace(buf.whole_lines(3, 3), "");
+        buf.commit_change();
+        assert_eq!((buf.mark('a'), buf.mark('b')), (None, Some(3)));
+
+        buf.begin_change();
+        buf.move_lines(3, 3, 0);
+        buf.commit_change();
+        assert_eq!(buf.mark('b'), Some(1));
+
+        buf.undo();
+        buf.undo();
+        assert_eq!((buf.mark('a'), buf.mark('b')), (Some(2), Some(3)));
+        buf.redo();
+        assert_eq!((buf.mark('a'), buf.mark('b')), (None, Some(3)));
+    }
+
+    #[test]
+    fn test_anchor_on_joined_line() {
+        let mut buf = Buffer::with_contents("one\ntwo\nthree\n");
+        let one = buf.anchor(1);
+        let two = buf.anchor(2);
+        let three = buf.anchor(3);
+
+        let Region(_, end) = buf.line_at(1);
+        buf.replace(Region(end, end + 1), " ");
+        assert_eq!(buf.text(), "one two\nthree\n");
+        assert_eq!(buf.anchor_line(&one), Some(1));
+        assert_eq!(buf.anchor_line(&two), None);
+        assert_eq!(buf.anchor_line(&three), Some(2));
+    }
+
+    #[test]
+    fn test_missing_newline() {
+        let mut buffer = Buffer::with_contents("one\ntwo");
+        assert!(buffer.missing_newline);
+        assert_eq!(buffer.line_count(), 2);
+
+        buffer.begin_change();
+        buffer.move_lines(2, 2, 0);
+        buffer.commit_change();
+        assert_eq!(buffer.text(), "two\none\n");
+        assert!(!buffer.missing_newline);
+        buffer.undo();
+        assert!(buffer.missing_newline);
+        buffer.redo();
+        assert!(!buffer.missing_newline);
+        assert!(!Buffer::with_contents("one\n").missing_newline);
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut buf = Buffer::with_contents("one\nnaïve 中文\n");
+
+        let p = buf.point_at(Position::new(2, 6), ColumnUnit::Graphemes);
+        assert_eq!(buf.position(&p, ColumnUnit::Width), Position::new(2, 6));
+        assert_eq!(buf.position(&p, ColumnUnit::Bytes), Position::new(2, 7));
+
+        let r = buf.region_between(Position::new(2, 9), Position::new(2, 2), ColumnUnit::Width);
+        assert_eq!(buf.region_text(&r), "ïve 中");
+
+        let r = buf.region_between(Position::new(2, 3), Position::new(2, 4), ColumnUnit::Bytes);
+        assert_eq!(buf.region_text(&r), "ï");
+        let r = buf.replace(r, "i");
+        assert_eq!(buf.region_text(&r), "i");
+        assert_eq!(buf.text(), "one\nnaive 中文\n");
+
+        let p = buf.point_at(Position::new(9, 100), ColumnUnit::Graphemes);
+        assert_eq!(buf.position(&p, ColumnUnit::Graphemes), Position::new(2, 8));
+    }
+
+    #[test]
+    fn test_fit_line() {
+        let buf = Buffer::with_contents("one
+中文字
+");
+
+        assert_eq!(buf.fit_line(1, 3), (Cow::from("one"), false));
+        assert_eq!(buf.fit_line(2, 5), (Cow::from("中文"), true));
+        assert_eq!(buf.fit_line(2, 6), (Cow::from("中文字"), false));
+    }
+
+    #[test]
+    fn test_mapped_file() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-buffer-mapped", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree").unwrap();
+        let buffer = Buffer::map_file(path.to_str().unwrap()).unwrap();
+
+        assert!(buffer.is_read_only());
+        assert!(buffer.has_line(3));
+        assert!(!buffer.has_line(4));
+        assert_eq!(buffer.clamp_line(7), 3);
+        assert_eq!(buffer.line_count(), 3);
+        assert_eq!(buffer.lines_text(2, 3), "two\nthree");
+        assert_eq!(collect_lines(buffer.lines_from(2)), vec!["two", "three"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn test_line_iter() {
         let buf = Buffer::with_contents("one\ntwo\n");
 
+        assert!(buf.has_line(0) && buf.has_line(2) && !buf.has_line(3));
+        assert!(!Buffer::default().has_line(1));
+
         let v = buf.lines().collect::<Vec<_>>();
-        assert_eq!(v[0], (1, "one"));
-        assert_eq!(v[1], (2, "two"));
+        assert_eq!(v[0], (1, Cow::from("one")));
+        assert_eq!(v[1], (2, Cow::from("two")));
     }
 
     #[test]
@@ -199,7 +784,7 @@ mod tests {
         assert_eq!(collect_lines(buf.lines_around(10, 4)), vec!["8", "9", "10", "11"]);
     }
 
-    fn collect_lines<'a>(i: impl Iterator<Item = (usize, &'a str)>) -> Vec<&'a str> {
+    fn collect_lines<'a>(i: impl Iterator<Item = (usize, Cow<'a, str>)>) -> Vec<Cow<'a, str>> {
         i.map(|(_, s)| s).collect()
     }
 }
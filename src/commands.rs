@@ -1,44 +1,861 @@
-use crate::editor::{CommandContext, CommandError, Editor, EditorMode};
+use std::{fs::{self, OpenOptions}, io::Write};
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::{
+    address::{self, split_delimited},
+    buffer::{Anchor, Buffer},
+    editor::{CommandContext, CommandError, Editor, EditorMode, IoError, ParseError, RuntimeError},
+    encoding::raw_byte,
+    line_ending::LineEnding,
+    map::CommandMap,
+    registers::Registers,
+    shell,
+    substitute::{PrintSuffix, Substitution},
+};
+
+/// Column at which `l` folds long lines, counting the `\` marking the fold.
+const LIST_WIDTH: usize = 72;
+
+pub fn print_line(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    print_range(ed, ctx, PrintSuffix::Print)
+}
+
+pub fn number(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    print_range(ed, ctx, PrintSuffix::Number)
+}
 
 pub fn list(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
-    for (n, line) in ed.buffer.lines() {
-        writeln!(ctx.output, "{} {}", n, line)?;
+    print_range(ed, ctx, PrintSuffix::List)
+}
+
+/// Prints the addressed lines, by default the current one, and makes the
+/// last of them current. A suffix can ask for a more detailed format, as in
+/// `pn`.
+fn print_range(ed: &mut Editor, ctx: &mut CommandContext, format: PrintSuffix) -> Result<(), CommandError> {
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
     }
+
+    print_lines(ed, ctx, start, end, format.max(suffix))?;
+    ed.buffer.line = end;
     Ok(())
 }
 
-pub fn print_line(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
-    if let Some(line) = ed.buffer.contents.lines().nth(ed.buffer.line - 1) {
-        writeln!(ctx.output, "{}", line)?;
-        Ok(())
-    } else {
-        Err(CommandError::Generic)
-    }
+/// Prints the number of the addressed line, or with no address the number
+/// of lines in the buffer.
+pub fn line_number(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let line = match ctx.range.resolve(ed)? {
+        Some((_, end)) => end,
+        None => ed.buffer.line_count(),
+    };
+    let suffix = PrintSuffix::parse(ctx.args)?;
+
+    writeln!(ctx.output, "{}", line).map_err(IoError::Output)?;
+    print_current(ed, ctx, suffix)
 }
 
 pub fn append(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
-    let text = ctx.input.read().map_err(|_| CommandError::Read)?;
-    let target_line = ctx.destination.shift(ed.buffer.line);
+    ed.check_writable()?;
+    let target_line = ctx.range.line_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    let text = ctx.input.read().map_err(IoError::Input)?;
     let p = ed.buffer.end_of_line(target_line);
-    let p = ed.buffer.insert(p, &text);
-    ed.buffer.line = ed.buffer.line_at_point(&p);
-    Ok(())
+    ed.buffer.insert(p, &text);
+    ed.buffer.line = target_line + text.matches('\n').count();
+    print_current(ed, ctx, suffix)
+}
+
+pub fn insert(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let target_line = ctx.range.line_or_current(ed)?.max(1);
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    let text = ctx.input.read().map_err(IoError::Input)?;
+    let p = ed.buffer.beginning_of_line(target_line);
+    ed.buffer.insert(p, &text);
+    ed.buffer.line = target_line + text.matches('\n').count().max(1) - 1;
+    print_current(ed, ctx, suffix)
+}
+
+pub fn delete(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let region = ed.buffer.whole_lines(start, end);
+    ed.registers.kill(ed.registers.selected, &ed.buffer.region_text(&region))?;
+    ed.buffer.replace(region, "");
+    ed.buffer.line = start.min(ed.buffer.line_count());
+    print_current(ed, ctx, suffix)
+}
+
+/// Marks the addressed line with the lowercase letter given after `k`.
+pub fn mark(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let line = ctx.range.line_or_current(ed)?;
+    let mut chars = ctx.args.chars();
+    let name = chars.next().filter(char::is_ascii_lowercase).ok_or(ParseError::InvalidMark)?;
+    let suffix = PrintSuffix::parse(chars.as_str())?;
+    if line == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    ed.buffer.set_mark(name, line);
+    print_current(ed, ctx, suffix)
+}
+
+/// Copies the addressed lines into the selected register.
+pub fn yank(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    ed.registers.set(ed.registers.selected, &ed.buffer.lines_text(start, end))?;
+    print_current(ed, ctx, suffix)
+}
+
+/// Inserts the selected register after the addressed line, which may be 0.
+pub fn put(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let line = ctx.range.line_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    let text = ed.registers.get(ed.registers.selected).ok_or(RuntimeError::EmptyRegister)?.to_owned();
+
+    let p = ed.buffer.end_of_line(line);
+    ed.buffer.insert(p, &text);
+    ed.buffer.line = line + text.matches('\n').count();
+    print_current(ed, ctx, suffix)
+}
+
+/// Runs the command following `"x` with register `x` selected, as in
+/// `1,3"ay` or `"Ad`.
+pub fn register(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let mut chars = ctx.args.chars();
+    let name = chars.next().ok_or(ParseError::InvalidRegister)?;
+    Registers::check_name(name)?;
+
+    let commands = ctx.commands.ok_or(ParseError::UnknownCommand)?;
+    let cmd = commands.lookup(chars.as_str())?
+        .with_range(ctx.range.clone());
+
+    ed.registers.selected = Some(name);
+    let result = cmd.run_nested(ed, ctx);
+    ed.registers.selected = None;
+    result
+}
+
+pub fn join(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = match ctx.range.resolve(ed)? {
+        Some(lines) => lines,
+        None => {
+            let line = ed.buffer.line.min(ed.buffer.line_count());
+            (line, line + 1)
+        },
+    };
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if start == 0 || end > ed.buffer.line_count() {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    if start < end {
+        let region = ed.buffer.lines_region(start, end);
+        let joined = ed.buffer.region_text(&region).replace('\n', "");
+        ed.buffer.replace(region, &joined);
+        ed.buffer.line = start;
+    }
+    print_current(ed, ctx, suffix)
+}
+
+pub fn move_lines(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let (dest, suffix) = destination(ed, ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+    if dest >= start && dest < end {
+        return Err(CommandError::Parse(ParseError::InvalidDestination));
+    }
+
+    ed.buffer.move_lines(start, end, dest);
+    ed.buffer.line = if dest >= end { dest } else { dest + end - start + 1 };
+    print_current(ed, ctx, suffix)
+}
+
+pub fn transfer(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let (dest, suffix) = destination(ed, ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let mut text = String::from(ed.buffer.lines_text(start, end));
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+
+    let p = ed.buffer.end_of_line(dest);
+    ed.buffer.insert(p, &text);
+    ed.buffer.line = dest + end - start + 1;
+    print_current(ed, ctx, suffix)
+}
+
+/// Parses the destination address given after `m` and `t`, and the print
+/// suffix following it.
+fn destination(ed: &mut Editor, args: &str) -> Result<(usize, PrintSuffix), CommandError> {
+    let (range, rest) = address::parse_range(args.trim())?;
+    let suffix = PrintSuffix::parse(rest).map_err(|_| ParseError::InvalidDestination)?;
+
+    Ok((range.line_or_current(ed)?, suffix))
 }
 
 pub fn change_line(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
-    let text = ctx.input.read().map_err(|_| CommandError::Read)?;
-    let target_line = ctx.destination.shift(ed.buffer.line);
-    let r = ed.buffer.replace(ed.buffer.line_at(target_line), text.trim_end());
-    ed.buffer.line = ed.buffer.region_line_number(&r);
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let text = ctx.input.read().map_err(IoError::Input)?;
+    let region = ed.buffer.whole_lines(start, end);
+    ed.registers.kill(ed.registers.selected, &ed.buffer.region_text(&region))?;
+    ed.buffer.replace(region, &text);
+    // Dot ends up on the last line typed in, or after the removed lines if
+    // there were none.
+    ed.buffer.line = match text.matches('\n').count() {
+        0 => start.min(ed.buffer.line_count()),
+        added => start + added - 1,
+    };
+    print_current(ed, ctx, suffix)
+}
+
+pub fn substitute(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let sub = Substitution::parse(ed, ctx.args)?;
+    let mut last_changed = None;
+    for n in start..=end {
+        let r = ed.buffer.line_at(n);
+        if let Some(changed) = sub.apply(&ed.buffer.region_text(&r)) {
+            ed.buffer.replace(r, &changed);
+            last_changed = Some(n);
+        }
+    }
+
+    let line = last_changed.ok_or(RuntimeError::NoMatch)?;
+    ed.buffer.line = line;
+    print_lines(ed, ctx, line, line, sub.print)
+}
+
+pub fn global(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    run_global(ed, ctx, true, false)
+}
+
+pub fn global_inverse(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    run_global(ed, ctx, false, false)
+}
+
+pub fn global_interactive(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    run_global(ed, ctx, true, true)
+}
+
+pub fn global_inverse_interactive(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    run_global(ed, ctx, false, true)
+}
+
+/// Marks every line in the range that matches (or doesn't match) the
+/// pattern, then runs the command list with each marked line as current.
+/// Lines deleted by earlier iterations are skipped.
+fn run_global(ed: &mut Editor, ctx: &mut CommandContext, matching: bool, interactive: bool) -> Result<(), CommandError> {
+    if ctx.nested {
+        return Err(CommandError::Parse(ParseError::NestedGlobal));
+    }
+    let map = ctx.commands.ok_or(ParseError::UnknownCommand)?;
+    let (start, end) = ctx.range.resolve(ed)?.unwrap_or((1, ed.buffer.line_count()));
+    let delim = ctx.args.chars().next()
+        .filter(|c| !c.is_whitespace() && *c != '\\')
+        .ok_or(ParseError::InvalidSuffix)?;
+
+    let (pattern, rest) = split_delimited(&ctx.args[delim.len_utf8()..], delim);
+    let re = ed.search_pattern(&pattern)?;
+
+    let mut command_list = String::from(rest);
+    while command_list.ends_with('\\') {
+        command_list.pop();
+        command_list.push('\n');
+        let line = ctx.input.read_line().map_err(IoError::Input)?;
+        command_list.push_str(line.trim_end_matches('\n'));
+    }
+    if command_list.trim().is_empty() {
+        command_list = String::from("p");
+    }
+
+    let marked = ed.buffer.lines_from(start)
+        .take(end + 1 - start.max(1))
+        .filter(|(_, line)| re.is_match(line) == matching)
+        .map(|(n, _)| n)
+        .collect::<Vec<_>>();
+    let anchors = marked.into_iter().map(|n| ed.buffer.anchor(n)).collect::<Vec<_>>();
+
+    let result = run_marked(ed, ctx, map, &anchors, &command_list, interactive);
+    for anchor in anchors {
+        ed.buffer.release(anchor);
+    }
+    result
+}
+
+fn run_marked(
+    ed: &mut Editor,
+    ctx: &mut CommandContext,
+    map: &CommandMap,
+    anchors: &[Anchor],
+    command_list: &str,
+    interactive: bool,
+) -> Result<(), CommandError> {
+    let mut previous: Option<String> = None;
+    for anchor in anchors {
+        let Some(line) = ed.buffer.anchor_line(anchor) else {
+            continue;
+        };
+        ed.buffer.line = line;
+
+        let commands = if interactive {
+            print_lines(ed, ctx, line, line, PrintSuffix::Print)?;
+            let input = ctx.input.read_line().map_err(IoError::Input)?;
+            match input.trim_end_matches('\n') {
+                "" => continue,
+                "&" => previous.clone().ok_or(ParseError::UnknownCommand)?,
+                cmd => String::from(cmd),
+            }
+        } else {
+            String::from(command_list)
+        };
+
+        // As in POSIX ed, a command that finds nothing to change on one of
+        // the lines, like `s` without a match, doesn't stop the others.
+        for cmd_str in commands.lines() {
+            match map.lookup(cmd_str)?.run_nested(ed, ctx) {
+                Ok(()) | Err(CommandError::Runtime(RuntimeError::NoMatch)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        previous = Some(commands);
+    }
+
     Ok(())
 }
 
+pub fn write(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let (quit, target) = match ctx.args.strip_prefix('q') {
+        Some(rest) => (true, rest.trim()),
+        None => (false, ctx.args.trim()),
+    };
+
+    write_lines(ed, ctx, target, false)?;
+    if quit {
+        ed.kill();
+    }
+    Ok(())
+}
+
+pub fn write_append(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    write_lines(ed, ctx, ctx.args.trim(), true)
+}
+
+fn write_lines(ed: &mut Editor, ctx: &mut CommandContext, target: &str, append: bool) -> Result<(), CommandError> {
+    let (start, end) = ctx.range.resolve(ed)?.unwrap_or((1, ed.buffer.line_count()));
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let whole_buffer = start == 1 && end == ed.buffer.line_count();
+    let lines = ed.buffer.lines_text(start, end);
+    let lines = match lines.strip_suffix('\n') {
+        Some(unterminated) if ed.buffer.missing_newline && end == ed.buffer.line_count() => unterminated,
+        _ => &lines,
+    };
+    let text = ed.buffer.line_ending.apply(lines);
+    let text = ed.buffer.encoding.encode(&text).map_err(IoError::Unencodable)?.into_owned();
+    let len = text.len();
+    if let Some(cmd) = target.strip_prefix('!') {
+        let cmd = shell_command(ed, ctx, cmd)?;
+        let output = ed.run_shell(&cmd, Some(&text))?;
+        ctx.output.write_all(&output.stdout).map_err(IoError::Output)?;
+    } else {
+        let path = if target.is_empty() {
+            ed.filename.as_deref().ok_or(IoError::NoFileName)?
+        } else {
+            target
+        };
+
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .and_then(|mut file| file.write_all(&text))
+            .map_err(|e| IoError::File(String::from(path), e))?;
+        if whole_buffer {
+            ed.buffer.modified = false;
+        }
+
+        if ed.filename.is_none() {
+            ed.filename = Some(String::from(target));
+        }
+    }
+
+    report(ed, ctx, len)?;
+    Ok(())
+}
+
+pub fn edit(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.confirm_discard(ed.buffer_id)?;
+    edit_file(ed, ctx)
+}
+
+pub fn edit_force(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    edit_file(ed, ctx)
+}
+
+fn edit_file(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let target = ctx.args.trim();
+    ed.buffer = load_buffer(ed, ctx, target)?;
+    if !target.is_empty() && !target.starts_with('!') {
+        ed.filename = Some(String::from(target));
+    }
+    Ok(())
+}
+
+/// Loads a file, or the output of a `!` command, into a new buffer and
+/// prints its size. Files are mapped instead in read-only mode.
+fn load_buffer(ed: &mut Editor, ctx: &mut CommandContext, target: &str) -> Result<Buffer, CommandError> {
+    if ed.read_only && !target.starts_with('!') {
+        let path = match target {
+            "" => ed.filename.as_deref().ok_or(IoError::NoFileName)?,
+            target => target,
+        };
+        let file_error = |e| IoError::File(String::from(path), e);
+        let mut buffer = Buffer::map_file(path).map_err(file_error)?;
+        let len = fs::metadata(path).map_err(file_error)?.len();
+        buffer.line = buffer.line_count();
+        report(ed, ctx, len)?;
+        return Ok(buffer);
+    }
+
+    let bytes = read_source(ed, ctx, target)?;
+    let mut buffer = Buffer::decode(&bytes, ed.encoding);
+    buffer.line = buffer.line_count();
+    if buffer.missing_newline {
+        report(ed, ctx, "newline appended")?;
+    }
+    report(ed, ctx, bytes.len())?;
+    Ok(buffer)
+}
+
+/// Lists the open buffers, or switches to the one numbered in the
+/// arguments. The list shows each buffer's number, `*` for the current
+/// one, `+` if it is modified, its line count and its file name.
+pub fn buffers(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let args = ctx.args.trim();
+    if !args.is_empty() {
+        return ed.switch_buffer(buffer_number(args)?);
+    }
+
+    for (id, buffer, name) in ed.buffer_list() {
+        let current = if id == ed.buffer_id { '*' } else { ' ' };
+        let modified = if buffer.modified { '+' } else { ' ' };
+        writeln!(ctx.output, "{}{}{}\t{}\t{}", id, current, modified, buffer.line_count(), name.unwrap_or("")).map_err(IoError::Output)?;
+    }
+    Ok(())
+}
+
+/// Opens a file, or an empty buffer when no file is given, alongside the
+/// current buffer and switches to it.
+pub fn buffer_open(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let target = ctx.args.trim();
+    if target.is_empty() {
+        ed.open_buffer(Buffer::default(), None);
+        return Ok(());
+    }
+
+    let buffer = load_buffer(ed, ctx, target)?;
+    let name = (!target.starts_with('!')).then(|| String::from(target));
+    ed.open_buffer(buffer, name);
+    Ok(())
+}
+
+/// Closes the buffer numbered in the arguments, or the current one. Like
+/// `q`, a modified buffer is only closed when asked twice.
+pub fn buffer_close(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let args = ctx.args.trim();
+    let id = if args.is_empty() { ed.buffer_id } else { buffer_number(args)? };
+    ed.confirm_discard(id)?;
+    ed.close_buffer(id)
+}
+
+/// Copies the addressed lines into another buffer, after the line given
+/// following the buffer number (`$` if omitted).
+pub fn transfer_to_buffer(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let text = ed.buffer.lines_text(start, end).into_owned();
+    insert_in_buffer(ed, ctx.args, &text)
+}
+
+/// Like `T`, but removes the lines from the current buffer.
+pub fn move_to_buffer(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let (start, end) = ctx.range.lines_or_current(ed)?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let text = ed.buffer.lines_text(start, end).into_owned();
+    insert_in_buffer(ed, ctx.args, &text)?;
+    ed.buffer.replace(ed.buffer.whole_lines(start, end), "");
+    ed.buffer.line = start.saturating_sub(1).max(1).min(ed.buffer.line_count());
+    Ok(())
+}
+
+/// Inserts `text` into the buffer named by `args` (a buffer number and an
+/// optional address), leaving dot there on the last inserted line.
+fn insert_in_buffer(ed: &mut Editor, args: &str, text: &str) -> Result<(), CommandError> {
+    let args = args.trim_start();
+    let split = args.find(|c: char| !c.is_ascii_digit()).unwrap_or(args.len());
+    let id = buffer_number(&args[..split])?;
+    let address = args[split..].trim();
+    if id == ed.buffer_id {
+        return Err(CommandError::Parse(ParseError::InvalidDestination));
+    }
+
+    let source = ed.buffer_id;
+    ed.switch_buffer(id)?;
+    let result = insert_at_address(ed, address, text);
+    ed.switch_buffer(source)?;
+    result
+}
+
+fn insert_at_address(ed: &mut Editor, address: &str, text: &str) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let dest = match address {
+        "" => ed.buffer.line_count(),
+        address => match destination(ed, address)? {
+            (line, PrintSuffix::None) => line,
+            _ => return Err(CommandError::Parse(ParseError::InvalidDestination)),
+        },
+    };
+    let p = ed.buffer.end_of_line(dest);
+    ed.buffer.insert(p, text);
+    ed.buffer.line = dest + text.matches('\n').count();
+    Ok(())
+}
+
+fn buffer_number(s: &str) -> Result<usize, CommandError> {
+    s.trim().parse().map_err(|_| CommandError::Runtime(RuntimeError::NoSuchBuffer))
+}
+
+pub fn read(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let line = ctx.range.resolve(ed)?.map(|(_, end)| end).unwrap_or(ed.buffer.line_count());
+    let target = ctx.args.trim();
+    let source = read_source(ed, ctx, target)?;
+    if ed.filename.is_none() && !target.starts_with('!') {
+        ed.filename = Some(String::from(target));
+    }
+
+    let len = source.len();
+    let source = ed.encoding.unwrap_or(ed.buffer.encoding).decode(&source);
+    let mut text = LineEnding::detect(&source).normalize(&source).into_owned();
+    let unterminated = !text.is_empty() && !text.ends_with('\n');
+    if unterminated {
+        text.push('\n');
+        report(ed, ctx, "newline appended")?;
+    }
+
+    let at_end = line == ed.buffer.line_count();
+    let p = ed.buffer.end_of_line(line);
+    ed.buffer.insert(p, &text);
+    ed.buffer.line = line + text.matches('\n').count();
+    if unterminated && at_end {
+        ed.buffer.missing_newline = true;
+    }
+
+    report(ed, ctx, len)?;
+    Ok(())
+}
+
+pub fn filename(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let name = ctx.args.trim();
+    if !name.is_empty() {
+        ed.filename = Some(String::from(name));
+    }
+
+    let name = ed.filename.as_deref().ok_or(IoError::NoFileName)?;
+    match ed.buffer.line_ending {
+        LineEnding::Lf => writeln!(ctx.output, "{}", name).map_err(IoError::Output)?,
+        style => writeln!(ctx.output, "{} [{}]", name, style).map_err(IoError::Output)?,
+    }
+    Ok(())
+}
+
+/// Prints the line ending style used when writing the buffer, or switches
+/// it to the one named in the arguments (`lf`, `crlf` or `cr`). `eol` and
+/// `noeol` choose whether the last line is written with a newline.
+pub fn line_ending(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let name = ctx.args.trim();
+    match name {
+        "" if ed.buffer.missing_newline => writeln!(ctx.output, "{} noeol", ed.buffer.line_ending).map_err(IoError::Output)?,
+        "" => writeln!(ctx.output, "{}", ed.buffer.line_ending).map_err(IoError::Output)?,
+        "eol" | "noeol" => {
+            ed.check_writable()?;
+            let missing = name == "noeol";
+            if missing != ed.buffer.missing_newline {
+                ed.buffer.missing_newline = missing;
+                ed.buffer.modified = true;
+            }
+        },
+        _ => {
+            ed.check_writable()?;
+            let style = name.parse().map_err(|_| ParseError::InvalidLineEnding)?;
+            if style != ed.buffer.line_ending {
+                ed.buffer.line_ending = style;
+                ed.buffer.modified = true;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Reads a file, or the output of a shell command when `target` starts with
+/// `!`. An empty target means the remembered file name.
+fn read_source(ed: &mut Editor, ctx: &mut CommandContext, target: &str) -> Result<Vec<u8>, CommandError> {
+    if let Some(cmd) = target.strip_prefix('!') {
+        let cmd = shell_command(ed, ctx, cmd)?;
+        Ok(ed.run_shell(&cmd, None)?.stdout)
+    } else {
+        let path = match target {
+            "" => ed.filename.as_deref().ok_or(IoError::NoFileName)?,
+            target => target,
+        };
+        fs::read(path).map_err(|e| IoError::File(String::from(path), e).into())
+    }
+}
+
+/// Runs a shell command. Without an address its output is printed and
+/// followed by `!`; with one, the addressed lines are piped through the
+/// command and replaced by what it prints, as in `1,10!sort`.
+pub fn shell(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    let lines = ctx.range.resolve(ed)?;
+    let cmd = shell_command(ed, ctx, ctx.args)?;
+    let Some((start, end)) = lines else {
+        let output = ed.run_shell(&cmd, None)?;
+        ctx.output.write_all(&output.stdout).map_err(IoError::Output)?;
+        report(ed, ctx, "!")?;
+        return Ok(());
+    };
+
+    ed.check_writable()?;
+    if start == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    let region = ed.buffer.whole_lines(start, end);
+    let input = ed.buffer.encoding.encode(&ed.buffer.region_text(&region)).map_err(IoError::Unencodable)?.into_owned();
+    let output = ed.run_shell(&cmd, Some(&input))?;
+    // A failing filter (a formatter rejecting its input, say) must not
+    // wipe out the lines it was given.
+    if !output.success {
+        return Err(CommandError::Io(IoError::CommandFailed));
+    }
+
+    let text = ed.buffer.encoding.decode(&output.stdout);
+    let mut text = LineEnding::detect(&text).normalize(&text).into_owned();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+
+    ed.buffer.replace(region, &text);
+    ed.buffer.line = (start - 1 + text.matches('\n').count()).min(ed.buffer.line_count());
+    Ok(())
+}
+
+/// Prints a byte count or similar note, unless the editor is silent.
+fn report(ed: &Editor, ctx: &mut CommandContext, note: impl std::fmt::Display) -> Result<(), CommandError> {
+    if !ed.silent {
+        writeln!(ctx.output, "{}", note).map_err(IoError::Output)?;
+    }
+    Ok(())
+}
+
+/// Expands `%` and a leading `!` in a shell command and remembers the
+/// result for `!!`. An expanded command is printed before it runs.
+fn shell_command(ed: &mut Editor, ctx: &mut CommandContext, cmd: &str) -> Result<String, CommandError> {
+    let (cmd, expanded) = shell::expand(cmd.trim(), ed.last_command.as_deref(), ed.filename.as_deref())?;
+    if expanded {
+        writeln!(ctx.output, "{}", cmd).map_err(IoError::Output)?;
+    }
+
+    ed.last_command = Some(cmd.clone());
+    Ok(cmd)
+}
+
+pub fn undo(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if !ed.buffer.undo() {
+        return Err(CommandError::Runtime(RuntimeError::NothingToUndo));
+    }
+    print_current(ed, ctx, suffix)
+}
+
+pub fn redo(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.check_writable()?;
+    let suffix = PrintSuffix::parse(ctx.args)?;
+    if !ed.buffer.redo() {
+        return Err(CommandError::Runtime(RuntimeError::NothingToRedo));
+    }
+    print_current(ed, ctx, suffix)
+}
+
 pub fn goto_line(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
-    ed.buffer.line = ctx.destination.shift(ed.buffer.line);
+    ed.buffer.line = ctx.range.line_or_current(ed)?;
+    Ok(())
+}
+
+fn print_lines(ed: &Editor, ctx: &mut CommandContext, start: usize, end: usize, suffix: PrintSuffix) -> Result<(), CommandError> {
+    if suffix == PrintSuffix::None {
+        return Ok(());
+    }
+
+    for (n, line) in ed.buffer.lines_from(start).take(end + 1 - start) {
+        match suffix {
+            PrintSuffix::None => (),
+            PrintSuffix::Print => write_line(ctx.output, &line).map_err(IoError::Output)?,
+            PrintSuffix::Number => {
+                write!(ctx.output, "{}\t", n).map_err(IoError::Output)?;
+                write_line(ctx.output, &line).map_err(IoError::Output)?;
+            },
+            PrintSuffix::List => ctx.output.write_all(list_line(&line).as_bytes()).map_err(IoError::Output)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the current line in the format asked for by a command's suffix.
+fn print_current(ed: &Editor, ctx: &mut CommandContext, suffix: PrintSuffix) -> Result<(), CommandError> {
+    let line = ed.buffer.line;
+    if suffix != PrintSuffix::None && line == 0 {
+        return Err(CommandError::Parse(ParseError::InvalidAddress));
+    }
+
+    print_lines(ed, ctx, line, line, suffix)
+}
+
+/// Writes `line` and a newline, with raw bytes written back as they were
+/// read.
+fn write_line(out: &mut dyn Write, line: &str) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(line.len() + 1);
+    for c in line.chars() {
+        match raw_byte(c) {
+            Some(b) => bytes.push(b),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    bytes.push(b'\n');
+    out.write_all(&bytes)
+}
+
+/// Formats `line` the way `l` shows it: backslashes, dollar signs and
+/// unprintable characters escaped, long lines folded with a `\`, and a `$`
+/// marking the end.
+fn list_line(line: &str) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    for c in line.chars() {
+        let mut piece = String::new();
+        match c {
+            '\\' => piece.push_str("\\\\"),
+            '$' => piece.push_str("\\$"),
+            '\u{7}' => piece.push_str("\\a"),
+            '\u{8}' => piece.push_str("\\b"),
+            '\u{c}' => piece.push_str("\\f"),
+            '\r' => piece.push_str("\\r"),
+            '\t' => piece.push_str("\\t"),
+            '\u{b}' => piece.push_str("\\v"),
+            c => push_escaped(&mut piece, c),
+        }
+
+        let width = if piece.starts_with('\\') { piece.len() } else { c.width().unwrap_or(0) };
+        if column > 0 && column + width >= LIST_WIDTH {
+            out.push_str("\\\n");
+            column = 0;
+        }
+        out.push_str(&piece);
+        column += width;
+    }
+
+    out.push_str("$\n");
+    out
+}
+
+/// Pushes `c`, written as octal byte escapes if it is a raw byte or a
+/// control character.
+fn push_escaped(out: &mut String, c: char) {
+    match raw_byte(c) {
+        Some(b) => out.push_str(&format!("\\{:03o}", b)),
+        None if c.is_control() => {
+            let mut bytes = [0; 4];
+            for b in c.encode_utf8(&mut bytes).bytes() {
+                out.push_str(&format!("\\{:03o}", b));
+            }
+        },
+        None => out.push(c),
+    }
+}
+
+/// Explains the last error.
+pub fn help(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    if let Some(error) = &ed.last_error {
+        writeln!(ctx.output, "{}", error).map_err(IoError::Output)?;
+    }
+    Ok(())
+}
+
+/// Toggles explaining errors as they happen, explaining the last one when
+/// turned on.
+pub fn help_mode(ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+    ed.explain_errors = !ed.explain_errors;
+    if ed.explain_errors {
+        help(ed, ctx)?;
+    }
     Ok(())
 }
 
 pub fn quit(ed: &mut Editor, _: &mut CommandContext) -> Result<(), CommandError> {
+    ed.confirm_quit()?;
+    ed.kill();
+    Ok(())
+}
+
+pub fn quit_force(ed: &mut Editor, _: &mut CommandContext) -> Result<(), CommandError> {
     ed.kill();
     Ok(())
 }
@@ -59,95 +876,763 @@ pub fn display(ed: &mut Editor, _: &mut CommandContext) -> Result<(), CommandErr
 
 #[cfg(test)]
 mod tests {
-    use std::io::BufWriter;
-
-    use crate::editor::TextInput;
+    use crate::{
+        editor::StringInput,
+        encoding::Encoding,
+        session::Session,
+        shell::{Shell, ShellOutput},
+    };
 
     use super::*;
 
     #[test]
     fn test_list() {
-        let mut ed = Editor::default();
-        let mut buf = BufWriter::new(Vec::new());
-        let mut ctx = CommandContext::with_output(&mut buf);
+        let mut session = Session::with_contents("a\\b\t$c\x07\x7f\n");
+
+        assert_eq!(session.run("l").unwrap().output, "a\\\\b\\t\\$c\\a\\177$\n");
+    }
+
+    #[test]
+    fn test_list_raw_bytes() {
+        let mut session = Session::new(Editor::with_buffer(Buffer::decode(b"a\xff\x01\tb\n", None), None));
+        let mut out = Vec::new();
+
+        session.run_with("l", &mut out, &StringInput::default()).unwrap();
+        session.run_with("p", &mut out, &StringInput::default()).unwrap();
+        assert_eq!(out, b"a\\377\\001\\tb$\na\xff\x01\tb\n");
+    }
 
-        ed.buffer.contents.push_str("the content");
-        list(&mut ed, &mut ctx).unwrap();
+    #[test]
+    fn test_list_folds_long_lines() {
+        let mut session = Session::with_contents(&format!("{}\t{}\n", "x".repeat(70), "y".repeat(80)));
+
+        let expected = format!("{}\\\n\\t{}\\\n{}$\n", "x".repeat(70), "y".repeat(69), "y".repeat(11));
+        assert_eq!(session.run("l").unwrap().output, expected);
+    }
+
+    #[test]
+    fn test_number() {
+        let mut session = Session::with_contents("one\ntwo\n");
+
+        let outcome = session.run("1,$n").unwrap();
+        assert_eq!(outcome.output, "1\tone\n2\ttwo\n");
+        assert_eq!(outcome.line, 2);
+    }
+
+    #[test]
+    fn test_line_number() {
+        let mut session = Session::with_contents("one\ntwo\nthree\n");
+        session.editor.buffer.line = 2;
+
+        assert_eq!(session.run("=").unwrap().output, "3\n");
+        assert_eq!(session.run(".=").unwrap().output, "2\n");
+        assert_eq!(session.run("$=").unwrap().output, "3\n");
+        assert_eq!(session.run("/one/=").unwrap().output, "1\n");
+        assert_eq!(session.buffer().line, 2);
+    }
+
+    #[test]
+    fn test_print_suffixes() {
+        let mut session = Session::with_contents("one\ntwo\nthree\nfour\n");
 
-        let output = buf.into_inner().unwrap();
-        assert_eq!(output, b"1 the content\n");
+        assert_eq!(session.run("1dp").unwrap().output, "two\n");
+        assert_eq!(session.run("2s/e/E/gn").unwrap().output, "2\tthrEE\n");
+        assert_eq!(session.run("1,2m$l").unwrap().output, "thrEE$\n");
+        assert_eq!(session.run("1pn").unwrap().output, "1\tfour\n");
+        assert_eq!(session.run("ul").unwrap().output, "thrEE$\n");
+        assert!(matches!(session.run("1dx"), Err(CommandError::Parse(ParseError::InvalidSuffix))));
+        assert!(matches!(session.run("1m$x"), Err(CommandError::Parse(ParseError::InvalidDestination))));
+        assert_eq!(session.buffer().text(), "two\nthrEE\nfour\n");
     }
 
     #[test]
     fn test_print_line() {
-        let mut ed = Editor::default();
-        let mut buf = BufWriter::new(Vec::new());
-        let mut ctx = CommandContext::with_output(&mut buf);
+        let mut session = Session::with_contents("first line\nsecond line");
+        session.editor.buffer.line = 2;
+
+        assert_eq!(session.run("p").unwrap().output, "second line\n");
+    }
 
-        ed.buffer.contents.push_str("first line\nsecond line");
-        ed.buffer.line = 2;
-        print_line(&mut ed, &mut ctx).unwrap();
+    #[test]
+    fn test_print_range() {
+        let mut session = Session::with_contents("first\nsecond\nthird\n");
 
-        let output = buf.into_inner().unwrap();
-        assert_eq!(output, b"second line\n");
+        let outcome = session.run("2,$p").unwrap();
+        assert_eq!(outcome.output, "second\nthird\n");
+        assert_eq!(outcome.line, 3);
     }
 
     #[test]
-    fn test_goto_line() {
-        let mut ed = Editor::default();
-        let mut out = std::io::stdout();
+    fn test_substitute() {
+        let mut session = Session::with_contents("foo\nbar\nboo\n");
+
+        let outcome = session.run("1,$s/o/0/gp").unwrap();
+        assert_eq!(session.buffer().text(), "f00\nbar\nb00\n");
+        assert_eq!(outcome.line, 3);
+        assert_eq!(outcome.output, "b00\n");
+    }
+
+    #[test]
+    fn test_substitute_no_match() {
+        let mut session = Session::with_contents("foo\n");
+
+        assert!(matches!(session.run("s/x/y/"), Err(CommandError::Runtime(RuntimeError::NoMatch))));
+        assert_eq!(session.buffer().text(), "foo\n");
+    }
+
+    #[test]
+    fn test_substitute_list_suffix() {
+        let mut session = Session::with_contents("x$\n");
+
+        assert_eq!(session.run("s/x/\ty/l").unwrap().output, "\\ty\\$$\n");
+    }
+
+    #[test]
+    fn test_global() {
+        let mut session = Session::with_contents("foo\nbar\nboo\n");
+
+        let outcome = session.run("g/o/s/o/0/").unwrap();
+        assert_eq!(session.buffer().text(), "f0o\nbar\nb0o\n");
+        assert_eq!((outcome.line, outcome.output.as_str()), (3, ""));
+    }
 
-        let mut ctx = CommandContext::with_output(&mut out).line(100);
-        goto_line(&mut ed, &mut ctx).unwrap();
-        assert_eq!(ed.buffer.line, 100);
+    #[test]
+    fn test_global_default_print() {
+        let mut session = Session::with_contents("foo\nbar\nbaz\n");
+
+        assert_eq!(session.run("v/a/").unwrap().output, "foo\n");
+    }
+
+    #[test]
+    fn test_global_multiline_command_list() {
+        let mut session = Session::with_contents("xx\nab\n");
+
+        let outcome = session.run_with_input("g/x/s/x/y/\\", "s/x/z/p\n").unwrap();
+        assert_eq!(session.buffer().text(), "yz\nab\n");
+        assert_eq!(outcome.output, "yz\n");
+    }
+
+    #[test]
+    fn test_global_ignores_no_match() {
+        let mut session = Session::with_contents("foo bar\nfoo\nfoo bar\n");
+
+        session.run("g/foo/s/bar/BAZ/").unwrap();
+        assert_eq!(session.buffer().text(), "foo BAZ\nfoo\nfoo BAZ\n");
+    }
+
+    #[test]
+    fn test_global_register() {
+        let mut session = Session::with_contents("a1\nb\na2\n");
+
+        session.run("g/a/\"Ay").unwrap();
+        session.run("$\"ax").unwrap();
+        assert_eq!(session.buffer().text(), "a1\nb\na2\na1\na2\n");
+    }
 
-        let mut ctx = CommandContext::with_output(&mut out).line_relative(5);
-        goto_line(&mut ed, &mut ctx).unwrap();
-        assert_eq!(ed.buffer.line, 105);
+    #[test]
+    fn test_global_skips_deleted_lines() {
+        let mut session = Session::with_contents("x1\nx2\nx3\nx4\n");
 
-        let mut ctx = CommandContext::with_output(&mut out).line_relative(-200);
-        goto_line(&mut ed, &mut ctx).unwrap();
-        assert_eq!(ed.buffer.line, 1);
+        session.run_with_input("g/x/.,+1c", "new\n.\nnew\n.\n").unwrap();
+        assert_eq!(session.buffer().text(), "new\nnew\n");
+    }
+
+    #[test]
+    fn test_global_nested() {
+        let mut session = Session::with_contents("x\n");
+
+        assert!(matches!(session.run("g/x/g/x/p"), Err(CommandError::Parse(ParseError::NestedGlobal))));
+    }
+
+    #[test]
+    fn test_global_interactive() {
+        let mut session = Session::with_contents("foo\nboo\nzoo\n");
+
+        let outcome = session.run_with_input("G/o/", "s/o/0/\n\n&\n").unwrap();
+        assert_eq!(session.buffer().text(), "f0o\nboo\nz0o\n");
+        assert_eq!(outcome.output, "foo\nboo\nzoo\n");
+    }
+
+    #[test]
+    fn test_write() {
+        let path = temp_path("test_write");
+        let mut session = Session::with_contents("first\nsecond\n");
+
+        assert_eq!(session.run(&format!("w {}", path)).unwrap().output, "13\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        assert_eq!(session.editor.filename.as_deref(), Some(path.as_str()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_keeps_line_endings() {
+        let path = temp_path("test_write_keeps_line_endings");
+        let mut session = Session::with_contents("first\r\nsecond\r\n");
+
+        assert_eq!(session.buffer().lines_text(1, 1), "first\n");
+        assert_eq!(session.run(&format!("w {}", path)).unwrap().output, "15\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\r\nsecond\r\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_newline() {
+        let path = temp_path("test_missing_newline");
+        std::fs::write(&path, "one\ntwo").unwrap();
+        let mut session = Session::new(Editor::default());
+
+        assert_eq!(session.run(&format!("e {path}")).unwrap().output, "newline appended\n7\n");
+        session.run_with_input("a", "three\n.\n").unwrap();
+        assert_eq!(session.run("w").unwrap().output, "14\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n");
+
+        session.run("u").unwrap();
+        assert_eq!(session.run("w").unwrap().output, "7\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo");
+
+        session.run("1m$").unwrap();
+        assert_eq!(session.run("w").unwrap().output, "8\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two\none\n");
+
+        session.run("u").unwrap();
+        assert_eq!(session.run("L").unwrap().output, "LF noeol\n");
+        session.run("L eol").unwrap();
+        assert_eq!(session.run("w").unwrap().output, "8\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_line_ending() {
+        let mut session = Session::new(Editor::with_buffer(Buffer::with_contents("a\r\n"), Some(String::from("win.txt"))));
+
+        assert_eq!(session.run("f").unwrap().output, "win.txt [CRLF]\n");
+        assert_eq!(session.run("L").unwrap().output, "CRLF\n");
+        assert!(session.run("L lf").unwrap().modified);
+        assert_eq!(session.buffer().line_ending, LineEnding::Lf);
+        assert!(matches!(session.run("L nl"), Err(CommandError::Parse(ParseError::InvalidLineEnding))));
+    }
+
+    #[test]
+    fn test_write_range_and_append() {
+        let path = temp_path("test_write_range_and_append");
+        let mut session = Session::new(Editor::with_buffer(Buffer::with_contents("one\ntwo\nthree\n"), Some(String::from("other"))));
+
+        session.run(&format!("2w {}", path)).unwrap();
+        session.run(&format!("3W {}", path)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two\nthree\n");
+        assert_eq!(session.editor.filename.as_deref(), Some("other"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_quit() {
+        let path = temp_path("test_write_quit");
+        let mut session = Session::new(Editor::with_buffer(Buffer::with_contents("text\n"), Some(path.clone())));
+
+        assert!(session.run("wq").unwrap().quit);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_no_file_name() {
+        let mut session = Session::default();
+
+        assert!(matches!(session.run("w"), Err(CommandError::Io(IoError::NoFileName))));
+    }
+
+    #[test]
+    fn test_write_to_command() {
+        let path = temp_path("test_write_to_command");
+        let mut session = Session::with_contents("piped\n");
+
+        assert_eq!(session.run(&format!("w !cat > {}", path)).unwrap().output, "6\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "piped\n");
+        assert!(session.editor.filename.is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_shell() {
+        let mut session = Session::new(Editor::default().shell(StubShell));
+
+        assert!(matches!(session.run("!!"), Err(CommandError::Io(IoError::NoPreviousCommand))));
+        assert!(matches!(session.run("!wc %"), Err(CommandError::Io(IoError::NoFileName))));
+        assert_eq!(session.run("!ls").unwrap().output, "ls\n!\n");
+        session.editor.filename = Some(String::from("main.rs"));
+        assert_eq!(session.run("!! -l %").unwrap().output, "ls -l main.rs\nls -l main.rs\n!\n");
+        assert_eq!(session.run("!echo \\%").unwrap().output, "echo %\n!\n");
+        assert_eq!(session.editor.last_command.as_deref(), Some("echo %"));
+
+        let error = session.run("!missing").unwrap_err();
+        assert!(matches!(&error, CommandError::Io(IoError::Shell(command, _)) if command == "missing"));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn test_shell_filter() {
+        let mut session = Session::new(Editor::with_buffer(Buffer::with_contents("d\nc\nb\na\n"), None).shell(StubShell));
+
+        let outcome = session.run("2,4!sort").unwrap();
+        assert_eq!(session.buffer().text(), "d\na\nb\nc\n");
+        assert_eq!((outcome.line, outcome.output.as_str()), (4, ""));
+
+        assert!(matches!(session.run("1,2!false"), Err(CommandError::Io(IoError::CommandFailed))));
+        assert_eq!(session.buffer().text(), "d\na\nb\nc\n");
+
+        session.run("u").unwrap();
+        assert_eq!(session.buffer().text(), "d\nc\nb\na\n");
+    }
+
+    #[test]
+    fn test_silent() {
+        let path = temp_path("test_silent");
+        std::fs::write(&path, "one").unwrap();
+        let mut session = Session::new(Editor::default().shell(StubShell).silent(true));
+
+        assert_eq!(session.run(&format!("e {}", path)).unwrap().output, "");
+        assert_eq!(session.run("r !ls").unwrap().output, "");
+        assert_eq!(session.run("w").unwrap().output, "");
+        assert_eq!(session.run("!true").unwrap().output, "true\n");
+        assert_eq!(session.run(",p").unwrap().output, "one\nls\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_help() {
+        let mut session = Session::with_contents("one\n");
+
+        assert_eq!(session.run("h").unwrap().output, "");
+        assert!(matches!(session.run("5p"), Err(CommandError::Parse(ParseError::AddressOutOfRange))));
+        assert_eq!(session.run("h").unwrap().output, "address out of range\n");
+        assert_eq!(session.run("H").unwrap().output, "address out of range\n");
+        assert!(session.editor.explain_errors);
+        assert!(matches!(session.run("r /nonexistent/file"), Err(CommandError::Io(IoError::File(..)))));
+        assert!(session.run("h").unwrap().output.starts_with("/nonexistent/file: "));
+        assert_eq!(session.run("H").unwrap().output, "");
+        assert!(!session.editor.explain_errors);
+    }
+
+    #[test]
+    fn test_edit() {
+        let path = temp_path("test_edit");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut session = Session::with_contents("old\n");
+
+        let outcome = session.run(&format!("e {}", path)).unwrap();
+        assert_eq!(session.buffer().text(), "one\ntwo\n");
+        assert_eq!((outcome.line, outcome.output.as_str()), (2, "8\n"));
+        assert_eq!(session.editor.filename.as_deref(), Some(path.as_str()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_buffer() {
+        let path = temp_path("test_read_only");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut session = Session::new(Editor::with_buffer(Buffer::map_file(&path).unwrap(), None));
+
+        assert_eq!(session.run("1p").unwrap().output, "one\n");
+        assert!(matches!(session.run("1d"), Err(CommandError::Io(IoError::ReadOnly))));
+        assert!(matches!(session.run("1s/one/1/"), Err(CommandError::Io(IoError::ReadOnly))));
+
+        assert_eq!(session.run(&format!("e {}", path)).unwrap().output, "8\n");
+        assert!(!session.buffer().is_read_only());
+        session.run("1d").unwrap();
+        assert_eq!(session.buffer().text(), "two\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_invalid_utf8() {
+        let path = temp_path("test_read_only_invalid_utf8");
+        std::fs::write(&path, b"a\xffb\nsecond\nthird\n").unwrap();
+        let mut session = Session::new(Editor { buffer: Buffer::map_file(&path).unwrap(), ..Default::default() });
+
+        assert_eq!(session.run(",n").unwrap().output, "1\ta\u{fffd}b\n2\tsecond\n3\tthird\n");
+        assert_eq!(session.run("/third/n").unwrap().output, "3\tthird\n");
+        assert_eq!(session.run("?b?l").unwrap().output, "a\\377b$\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_crlf_round_trip() {
+        let path = temp_path("test_read_only_crlf");
+        let copy = temp_path("test_read_only_crlf_copy");
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+        let mut session = Session::new(Editor::default().read_only(true));
+
+        assert_eq!(session.run(&format!("e {}", path)).unwrap().output, "10\n");
+        assert!(session.buffer().is_read_only());
+        assert_eq!(session.buffer().line, 2);
+        assert_eq!(session.run(",l").unwrap().output, "one$\ntwo$\n");
+        session.run(&format!("w {}", copy)).unwrap();
+        assert_eq!(std::fs::read(&copy).unwrap(), b"one\r\ntwo\r\n");
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(copy).unwrap();
+    }
+
+    #[test]
+    fn test_encoding_round_trip() {
+        let path = temp_path("test_encoding_round_trip");
+        std::fs::write(&path, b"caf\xe9\n").unwrap();
+        let mut session = Session::default();
+
+        session.run(&format!("e {}", path)).unwrap();
+        assert_eq!(session.buffer().encoding, Encoding::Latin1);
+        assert_eq!(session.buffer().text(), "café\n");
+
+        session.run("1s/é/e\u{301}/").unwrap();
+        assert!(matches!(session.run("w"), Err(CommandError::Io(IoError::Unencodable('\u{301}')))));
+
+        session.run("1s/e.$/\u{e8}/").unwrap();
+        session.run("w").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe8\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffers() {
+        let path = temp_path("test_buffers");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut session = Session::new(Editor::with_buffer(Buffer::with_contents("a\n"), Some(String::from("first"))));
+        session.editor.buffer.modified = true;
+
+        assert_eq!(session.run(&format!("B {}", path)).unwrap().output, "8\n");
+        assert_eq!((session.editor.buffer_id, session.buffer().text().as_str()), (2, "one\ntwo\n"));
+        let expected = format!("1 +\t1\tfirst\n2* \t2\t{}\n", path);
+        assert_eq!(session.run("b").unwrap().output, expected);
+
+        session.run("b 1").unwrap();
+        assert_eq!(session.editor.filename.as_deref(), Some("first"));
+        assert!(matches!(session.run("b3"), Err(CommandError::Runtime(RuntimeError::NoSuchBuffer))));
+
+        assert!(matches!(session.run("C3"), Err(CommandError::Runtime(RuntimeError::NoSuchBuffer))));
+        assert!(matches!(session.run("C"), Err(CommandError::Runtime(RuntimeError::BufferModified))));
+        session.run("C").unwrap();
+        assert_eq!((session.editor.buffer_id, session.editor.filename.as_deref()), (2, Some(path.as_str())));
+        assert!(session.editor.other_buffers.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_between_buffers() {
+        let mut session = Session::with_contents("a\nb\nc\n");
+        session.run("B").unwrap();
+        session.run_with_input("a", "x\ny\n.\n").unwrap();
+        session.run("b 1").unwrap();
+
+        session.run("2T2 1").unwrap();
+        session.run("3M2").unwrap();
+        assert_eq!(session.buffer().text(), "a\nb\n");
+
+        session.run("b 2").unwrap();
+        assert_eq!(session.buffer().text(), "x\nb\ny\nc\n");
+        assert_eq!(session.buffer().line, 4);
+        session.run("u").unwrap();
+        assert_eq!(session.buffer().text(), "x\nb\ny\n");
+
+        session.run("b 1").unwrap();
+        session.run("u").unwrap();
+        assert_eq!(session.buffer().text(), "a\nb\nc\n");
+        assert!(matches!(session.run("1T1"), Err(CommandError::Parse(ParseError::InvalidDestination))));
+    }
+
+    #[test]
+    fn test_mark() {
+        let mut session = Session::with_contents("a\nb\nc\n");
+
+        session.run("2ka").unwrap();
+        session.run("1d").unwrap();
+        assert_eq!(session.run("'ap").unwrap().output, "b\n");
+        session.run("'ad").unwrap();
+        assert!(matches!(session.run("'ap"), Err(CommandError::Parse(ParseError::InvalidAddress))));
+        session.run("u").unwrap();
+        assert_eq!(session.run("'a,$p").unwrap().output, "b\nc\n");
+
+        assert!(matches!(session.run("kA"), Err(CommandError::Parse(ParseError::InvalidMark))));
+        assert!(matches!(session.run("k"), Err(CommandError::Parse(ParseError::InvalidMark))));
+    }
+
+    #[test]
+    fn test_yank_and_put() {
+        let mut session = Session::with_contents("a\nb\nc\n");
+
+        session.run("1,2y").unwrap();
+        session.run("3\"ay").unwrap();
+        session.run("1\"Ay").unwrap();
+        assert_eq!(session.run("$x").unwrap().line, 5);
+        assert_eq!(session.buffer().text(), "a\nb\nc\nc\na\n");
+
+        session.run("1,2y").unwrap();
+        assert_eq!(session.run("0\"ax").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "c\na\na\nb\nc\nc\na\n");
+        session.run("$x").unwrap();
+        assert!(session.buffer().text().ends_with("c\na\na\nb\n"));
+
+        assert!(matches!(session.run("\"zx"), Err(CommandError::Runtime(RuntimeError::EmptyRegister))));
+        assert!(matches!(session.run("\"!y"), Err(CommandError::Parse(ParseError::InvalidRegister))));
+    }
+
+    #[test]
+    fn test_kill_ring() {
+        let mut session = Session::with_contents("a\nb\nc\nd\n");
+
+        session.run("1d").unwrap();
+        session.run("1\"qd").unwrap();
+        session.run_with_input("1c", "new\n.\n").unwrap();
+        assert_eq!(session.buffer().text(), "new\nd\n");
+
+        session.run("$\"2x").unwrap();
+        session.run("$\"qx").unwrap();
+        session.run("$x").unwrap();
+        assert_eq!(session.buffer().text(), "new\nd\nb\nb\nc\n");
+        assert!(matches!(session.run("1\"1d"), Err(CommandError::Parse(ParseError::InvalidRegister))));
+    }
+
+    #[test]
+    fn test_edit_command_output() {
+        let mut session = Session::new(Editor::with_buffer(Buffer::default(), Some(String::from("name"))));
+
+        session.run("E !printf 'a\\nb\\n'").unwrap();
+        assert_eq!(session.buffer().text(), "a\nb\n");
+        assert_eq!(session.editor.filename.as_deref(), Some("name"));
+    }
+
+    #[test]
+    fn test_read() {
+        let path = temp_path("test_read");
+        std::fs::write(&path, "inserted\n").unwrap();
+        let mut session = Session::with_contents("first\nlast\n");
+
+        let outcome = session.run(&format!("1r {}", path)).unwrap();
+        assert_eq!(session.buffer().text(), "first\ninserted\nlast\n");
+        assert_eq!((outcome.line, outcome.output.as_str()), (2, "9\n"));
+        assert_eq!(session.editor.filename.as_deref(), Some(path.as_str()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_command_output() {
+        let mut session = Session::with_contents("bottom\n");
+
+        session.run("0r !echo top").unwrap();
+        assert_eq!(session.buffer().text(), "top\nbottom\n");
+        assert!(session.editor.filename.is_none());
+    }
+
+    #[test]
+    fn test_filename() {
+        let mut session = Session::default();
+
+        assert!(matches!(session.run("f"), Err(CommandError::Io(IoError::NoFileName))));
+        assert_eq!(session.run("f notes.txt").unwrap().output, "notes.txt\n");
+        assert_eq!(session.editor.filename.as_deref(), Some("notes.txt"));
+    }
+
+    #[test]
+    fn test_quit_modified() {
+        let mut session = Session::default();
+        session.editor.buffer.modified = true;
+
+        assert!(matches!(session.run("q"), Err(CommandError::Runtime(RuntimeError::BufferModified))));
+        assert!(session.editor.mode == EditorMode::Command);
+        assert!(session.run("q").unwrap().quit);
+    }
+
+    #[test]
+    fn test_quit_force() {
+        let mut session = Session::default();
+        session.editor.buffer.modified = true;
+
+        assert!(session.run("Q").unwrap().quit);
+    }
+
+    #[test]
+    fn test_edit_modified() {
+        let mut session = Session::with_contents("old\n");
+        session.editor.buffer.modified = true;
+
+        assert!(matches!(session.run("e !echo new"), Err(CommandError::Runtime(RuntimeError::BufferModified))));
+        assert_eq!(session.buffer().text(), "old\n");
+
+        assert!(!session.run("E !echo new").unwrap().modified);
+        assert_eq!(session.buffer().text(), "new\n");
+    }
+
+    #[test]
+    fn test_write_clears_modified() {
+        let path = temp_path("test_write_clears_modified");
+        let mut session = Session::with_contents("one\ntwo\n");
+        session.editor.buffer.modified = true;
+
+        assert!(session.run(&format!("1w {}", path)).unwrap().modified);
+        assert!(!session.run(&format!("w {}", path)).unwrap().modified);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_undo_global() {
+        let mut session = Session::with_contents("foo\nbar\nboo\n");
+        session.editor.buffer.line = 2;
+
+        session.run("g/o/s/o/0/g").unwrap();
+        assert_eq!(session.buffer().text(), "f00\nbar\nb00\n");
+        assert_eq!(session.buffer().line, 3);
+
+        assert_eq!(session.run("u").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "foo\nbar\nboo\n");
+        assert!(matches!(session.run("u"), Err(CommandError::Runtime(RuntimeError::NothingToUndo))));
+
+        assert_eq!(session.run("U").unwrap().line, 3);
+        assert_eq!(session.buffer().text(), "f00\nbar\nb00\n");
+        assert!(matches!(session.run("U"), Err(CommandError::Runtime(RuntimeError::NothingToRedo))));
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut session = Session::with_contents("first\nlast\n");
+
+        assert_eq!(session.run_with_input("2i", "a\nb\n.\n").unwrap().line, 3);
+        assert_eq!(session.buffer().text(), "first\na\nb\nlast\n");
+
+        assert_eq!(session.run_with_input("0i", "top\n.\n").unwrap().line, 1);
+        assert_eq!(session.buffer().text(), "top\nfirst\na\nb\nlast\n");
+    }
+
+    #[test]
+    fn test_insert_empty_buffer() {
+        let mut session = Session::default();
+
+        session.run_with_input("i", "text\n.\n").unwrap();
+        assert_eq!(session.buffer().text(), "text\n");
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut session = Session::with_contents("1\n2\n3\n4\n");
+
+        assert_eq!(session.run("2,3d").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "1\n4\n");
+
+        assert_eq!(session.run("d").unwrap().line, 1);
+        assert_eq!(session.buffer().text(), "1\n");
+    }
+
+    #[test]
+    fn test_join() {
+        let mut session = Session::with_contents("a\nb\nc\nd\n");
+
+        session.run("j").unwrap();
+        assert_eq!(session.buffer().text(), "ab\nc\nd\n");
+
+        assert_eq!(session.run("1,$j").unwrap().line, 1);
+        assert_eq!(session.buffer().text(), "abcd\n");
+    }
+
+    #[test]
+    fn test_move() {
+        let mut session = Session::with_contents("1\n2\n3\n4\n");
+
+        assert_eq!(session.run("1,2m$").unwrap().line, 4);
+        assert_eq!(session.buffer().text(), "3\n4\n1\n2\n");
+
+        assert_eq!(session.run("4m0").unwrap().line, 1);
+        assert_eq!(session.buffer().text(), "2\n3\n4\n1\n");
+
+        assert!(matches!(session.run("1,3m2"), Err(CommandError::Parse(ParseError::InvalidDestination))));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut session = Session::with_contents("1\n2\n3\n");
+
+        assert_eq!(session.run("1,2t2").unwrap().line, 4);
+        assert_eq!(session.buffer().text(), "1\n2\n1\n2\n3\n");
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_goto_line() {
+        let mut session = Session::with_contents(&(1..=200).map(|i| format!("{i}\n")).collect::<String>());
+
+        assert_eq!(session.run("100").unwrap().line, 100);
+        assert_eq!(session.run("+5").unwrap().line, 105);
+        assert!(session.run("-200").is_err());
+        assert_eq!(session.buffer().line, 105);
     }
 
     #[test]
     fn test_append() {
-        let mut ed = Editor::default();
-        let mut out = std::io::sink();
-        let mut ctx = CommandContext::with_output(&mut out);
+        let mut session = Session::with_contents("first\n");
 
-        ed.buffer.contents.push_str("first\n");
-        ctx.input = &ConstInput("end\n");
-        append(&mut ed, &mut ctx).unwrap();
+        assert_eq!(session.run_with_input("a", "end\n.\n").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "first\nend\n");
 
-        assert_eq!(ed.buffer.contents, "first\nend\n");
-        assert_eq!(ed.buffer.line, 3);
+        assert_eq!(session.run_with_input("1a", "middle\n.\n").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "first\nmiddle\nend\n");
+    }
 
-        ed.buffer.line = 1;
-        ctx.input = &ConstInput("middle\n");
-        append(&mut ed, &mut ctx).unwrap();
+    #[test]
+    fn test_append_print_suffix() {
+        let mut session = Session::with_contents("a\nb\n");
 
-        assert_eq!(ed.buffer.contents, "first\nmiddle\nend\n");
-        assert_eq!(ed.buffer.line, 3);
+        assert_eq!(session.run_with_input("1ap", "new\n.\n").unwrap().output, "new\n");
+        assert_eq!(session.run_with_input("0a", "x\ny\n.\n").unwrap().line, 2);
+        assert_eq!(session.buffer().text(), "x\ny\na\nnew\nb\n");
     }
 
     #[test]
     fn test_change_line() {
-        let mut ed = Editor::default();
-        let mut out = std::io::sink();
-        let mut ctx = CommandContext::with_output(&mut out).line(2);
+        let mut session = Session::with_contents("first\nsecond\nthird\n");
+
+        session.run_with_input("2c", "changed\n.\n").unwrap();
+        assert_eq!(session.buffer().text(), "first\nchanged\nthird\n");
+    }
+
+    #[test]
+    fn test_change_range() {
+        let mut session = Session::with_contents("first\nsecond\nthird\n");
+
+        session.run_with_input("1,2c", "changed\n.\n").unwrap();
+        assert_eq!(session.buffer().text(), "changed\nthird\n");
+    }
+
+    #[test]
+    fn test_change_multiple_lines() {
+        let mut session = Session::with_contents("a\nb\nc\n");
 
-        ed.buffer.contents.push_str("first\nsecond\nthird\n");
-        ctx.input = &ConstInput("changed\n");
-        change_line(&mut ed, &mut ctx).unwrap();
+        let outcome = session.run_with_input("2cp", "q\nr\n.\n").unwrap();
+        assert_eq!(outcome.output, "r\n");
+        assert_eq!(outcome.line, 3);
+        assert_eq!(session.buffer().text(), "a\nq\nr\nc\n");
 
-        assert_eq!(ed.buffer.contents, "first\nchanged\nthird\n");
+        assert_eq!(session.run_with_input("$c", ".\n").unwrap().line, 3);
     }
 
-    struct ConstInput(&'static str);
-    impl TextInput for ConstInput {
-        fn read(&self) -> Result<String, ()> {
-            Ok(String::from(self.0))
+    /// Echoes the command when run without input, and sorts its input
+    /// otherwise. `false` fails without printing anything, and `missing`
+    /// cannot be started at all.
+    struct StubShell;
+    impl Shell for StubShell {
+        fn run(&self, command: &str, input: Option<&[u8]>) -> std::io::Result<ShellOutput> {
+            if command == "missing" {
+                return Err(std::io::ErrorKind::NotFound.into());
+            }
+            let stdout = match input {
+                _ if command == "false" => Vec::new(),
+                None => format!("{}\n", command).into_bytes(),
+                Some(input) => {
+                    let mut lines: Vec<_> = std::str::from_utf8(input).unwrap().lines().collect();
+                    lines.sort();
+                    lines.iter().map(|l| format!("{}\n", l)).collect::<String>().into_bytes()
+                },
+            };
+            Ok(ShellOutput { stdout, success: command != "false" })
         }
     }
 }
@@ -1,17 +1,244 @@
-use std::{error::Error, fmt::Display, io::Write, str::FromStr};
+use std::{cell::RefCell, collections::VecDeque, error::Error, fmt::Display, io::{self, Write}};
 
-use crate::buffer::Buffer;
+use regex::Regex;
+
+use crate::{
+    address::Range,
+    buffer::Buffer,
+    encoding::Encoding,
+    map::CommandMap,
+    registers::Registers,
+    shell::{Shell, ShellOutput, SystemShell},
+};
 
-#[derive(Default)]
 pub struct Editor {
-    pub buffer: Buffer,
-    pub mode: EditorMode,
+    pub(crate) buffer: Buffer,
+    pub(crate) mode: EditorMode,
+    pub(crate) last_pattern: Option<Regex>,
+    pub(crate) last_replacement: Option<String>,
+    pub(crate) filename: Option<String>,
+    pub(crate) encoding: Option<Encoding>,
+    pub(crate) warned: bool,
+    /// Number of the current buffer in the buffer list.
+    pub(crate) buffer_id: usize,
+    /// The other open buffers, most recently used last.
+    pub(crate) other_buffers: Vec<OpenBuffer>,
+    pub(crate) registers: Registers,
+    pub(crate) shell: Box<dyn Shell>,
+    /// The last command run with `!`, repeated by `!!`.
+    pub(crate) last_command: Option<String>,
+    /// Leaves out byte counts and other reports meant for people, as `-s`
+    /// does.
+    pub(crate) silent: bool,
+    /// Maps opened files instead of reading them.
+    pub(crate) read_only: bool,
+    /// Explanation of the last error, shown by `h`.
+    pub(crate) last_error: Option<String>,
+    /// Whether errors are explained as they happen, toggled by `H`.
+    pub(crate) explain_errors: bool,
+}
+
+/// An open buffer that is not the current one.
+pub struct OpenBuffer {
+    pub(crate) id: usize,
+    pub(crate) buffer: Buffer,
+    pub(crate) filename: Option<String>,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self {
+            buffer: Buffer::default(),
+            mode: EditorMode::default(),
+            last_pattern: None,
+            last_replacement: None,
+            filename: None,
+            encoding: None,
+            warned: false,
+            buffer_id: 1,
+            other_buffers: Vec::new(),
+            registers: Registers::default(),
+            shell: Box::new(SystemShell),
+            last_command: None,
+            silent: false,
+            read_only: false,
+            last_error: None,
+            explain_errors: false,
+        }
+    }
 }
 
 impl Editor {
-    pub fn kill(&mut self) {
+    /// An editor on `buffer`, read from `filename` if it has one.
+    pub fn with_buffer(buffer: Buffer, filename: Option<String>) -> Self {
+        Self { buffer, filename, ..Default::default() }
+    }
+
+    /// Leaves out byte counts and other reports meant for people, as `-s`
+    /// does.
+    pub fn silent(self, silent: bool) -> Self {
+        Self {
+            silent,
+            ..self
+        }
+    }
+
+    /// Maps files opened by `e` and `B` into memory read-only, as
+    /// `--read-only` does, instead of reading them into an editable buffer.
+    pub fn read_only(self, read_only: bool) -> Self {
+        Self {
+            read_only,
+            ..self
+        }
+    }
+
+    /// Encoding to read files in, instead of detecting it.
+    pub fn encoding(self, encoding: Option<Encoding>) -> Self {
+        Self {
+            encoding,
+            ..self
+        }
+    }
+
+    /// Runs shell commands with `shell` instead of `sh`.
+    pub fn shell(self, shell: impl Shell + 'static) -> Self {
+        Self {
+            shell: Box::new(shell),
+            ..self
+        }
+    }
+
+    pub fn is_visual(&self) -> bool {
+        self.mode == EditorMode::Visual
+    }
+
+    /// Whether errors should be explained as they happen, as after `H`.
+    pub fn explains_errors(&self) -> bool {
+        self.explain_errors
+    }
+
+    pub(crate) fn kill(&mut self) {
         self.mode = EditorMode::Quit;
     }
+
+    /// Refuses to discard buffer `id` the first time it's attempted if it is
+    /// modified. Repeating the command right after the warning goes through.
+    pub(crate) fn confirm_discard(&mut self, id: usize) -> Result<(), CommandError> {
+        let buffer = match self.other_buffers.iter().find(|b| b.id == id) {
+            Some(other) => &other.buffer,
+            None if id == self.buffer_id => &self.buffer,
+            None => return Err(CommandError::Runtime(RuntimeError::NoSuchBuffer)),
+        };
+        self.confirm(buffer.modified)
+    }
+
+    /// Like `confirm_discard`, but for every open buffer.
+    pub(crate) fn confirm_quit(&mut self) -> Result<(), CommandError> {
+        self.confirm(self.buffer.modified || self.other_buffers.iter().any(|b| b.buffer.modified))
+    }
+
+    fn confirm(&mut self, modified: bool) -> Result<(), CommandError> {
+        if modified && !self.warned {
+            self.warned = true;
+            Err(CommandError::Runtime(RuntimeError::BufferModified))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remembers `error` for `h`.
+    pub(crate) fn record_error(&mut self, error: &CommandError) {
+        self.last_error = Some(error.to_string());
+    }
+
+    /// Makes `buffer` the current buffer, keeping the previous one open.
+    pub(crate) fn open_buffer(&mut self, buffer: Buffer, filename: Option<String>) {
+        let id = self.other_buffers.iter().map(|b| b.id).fold(self.buffer_id, usize::max) + 1;
+        self.make_current(OpenBuffer { id, buffer, filename });
+    }
+
+    pub(crate) fn switch_buffer(&mut self, id: usize) -> Result<(), CommandError> {
+        if id == self.buffer_id {
+            return Ok(());
+        }
+
+        let i = self.other_buffers.iter().position(|b| b.id == id).ok_or(RuntimeError::NoSuchBuffer)?;
+        let next = self.other_buffers.remove(i);
+        self.make_current(next);
+        Ok(())
+    }
+
+    /// Closes buffer `id`. Closing the current buffer switches to the one
+    /// used before it, or to a new empty buffer if no other is open.
+    pub(crate) fn close_buffer(&mut self, id: usize) -> Result<(), CommandError> {
+        if id != self.buffer_id {
+            let i = self.other_buffers.iter().position(|b| b.id == id).ok_or(RuntimeError::NoSuchBuffer)?;
+            self.other_buffers.remove(i);
+            return Ok(());
+        }
+
+        match self.other_buffers.pop() {
+            Some(next) => {
+                self.buffer_id = next.id;
+                self.buffer = next.buffer;
+                self.filename = next.filename;
+            },
+            None => {
+                self.buffer_id += 1;
+                self.buffer = Buffer::default();
+                self.filename = None;
+            },
+        }
+        Ok(())
+    }
+
+    /// All open buffers with their names, ordered by number.
+    pub(crate) fn buffer_list(&self) -> Vec<(usize, &Buffer, Option<&str>)> {
+        let mut list: Vec<_> = self.other_buffers.iter()
+            .map(|b| (b.id, &b.buffer, b.filename.as_deref()))
+            .chain([(self.buffer_id, &self.buffer, self.filename.as_deref())])
+            .collect();
+        list.sort_by_key(|(id, _, _)| *id);
+        list
+    }
+
+    fn make_current(&mut self, next: OpenBuffer) {
+        // Edits in a buffer that is switched away from mid-command are
+        // undone on their own, apart from any made after switching back.
+        self.buffer.commit_change();
+        let previous = OpenBuffer {
+            id: std::mem::replace(&mut self.buffer_id, next.id),
+            buffer: std::mem::replace(&mut self.buffer, next.buffer),
+            filename: std::mem::replace(&mut self.filename, next.filename),
+        };
+        self.other_buffers.push(previous);
+    }
+
+    /// Fails if the buffer was opened read-only.
+    pub(crate) fn check_writable(&self) -> Result<(), CommandError> {
+        if self.buffer.is_read_only() {
+            Err(CommandError::Io(IoError::ReadOnly))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs `command` with the editor's shell.
+    pub(crate) fn run_shell(&self, command: &str, input: Option<&[u8]>) -> Result<ShellOutput, CommandError> {
+        self.shell.run(command, input).map_err(|e| IoError::Shell(String::from(command), e).into())
+    }
+
+    /// Compiles `pattern` and remembers it as the last search. An empty
+    /// pattern reuses the previously remembered one.
+    pub(crate) fn search_pattern(&mut self, pattern: &str) -> Result<Regex, CommandError> {
+        if pattern.is_empty() {
+            return self.last_pattern.clone().ok_or(CommandError::Runtime(RuntimeError::NoPreviousPattern));
+        }
+
+        let re = Regex::new(pattern).map_err(|_| ParseError::InvalidPattern)?;
+        self.last_pattern = Some(re.clone());
+        Ok(re)
+    }
 }
 
 #[derive(Default, PartialEq)]
@@ -23,23 +250,33 @@ pub enum EditorMode {
 }
 
 pub struct CommandContext<'a> {
-    pub destination: LineOffset,
+    pub range: Range,
+    pub args: &'a str,
     pub output: &'a mut dyn Write,
     pub input: &'a dyn TextInput,
+    pub commands: Option<&'a CommandMap>,
+    /// Set for commands run by another one, such as those in a `g` command
+    /// list, which may not start another global command.
+    pub nested: bool,
 }
 
 pub trait TextInput {
-    fn read(&self) -> Result<String, ()>;
+    fn read(&self) -> io::Result<String>;
+    fn read_line(&self) -> io::Result<String>;
 }
 
-struct StdTextInput;
+/// Input read from stdin. End of file is an `UnexpectedEof` error, as it
+/// is for `StringInput`.
+pub struct StdTextInput;
 impl TextInput for StdTextInput {
-    fn read(&self) -> Result<String, ()> {
+    fn read(&self) -> io::Result<String> {
         let mut buf = String::new();
         let mut last: usize = 0;
         loop {
-            let chars_read = std::io::stdin().read_line(&mut buf)
-                .map_err(|_| ())?;
+            let chars_read = io::stdin().read_line(&mut buf)?;
+            if chars_read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
             if buf[last..last+chars_read].trim_end() == "." {
                 buf.truncate(last);
                 return Ok(buf);
@@ -48,92 +285,47 @@ impl TextInput for StdTextInput {
             last += chars_read;
         }
     }
-}
-
-static DEFAULT_INPUT: StdTextInput = StdTextInput;
 
-impl<'a> CommandContext<'a> {
-    pub fn with_output<W: Write>(output: &'a mut W) -> Self {
-        Self {
-            output,
-            destination: LineOffset::default(),
-            input: &DEFAULT_INPUT
-        }
-    }
-
-    pub fn line(self, line: usize) -> Self {
-        Self {
-            destination: LineOffset::Absolute(line),
-            ..self
-        }
-    }
-
-    pub fn line_relative(self, n: isize) -> Self {
-        Self {
-            destination: LineOffset::Relative(n),
-            ..self
-        }
-    }
-
-    pub fn line_offset(self, o: &LineOffset) -> Self {
-        Self {
-            destination: *o,
-            ..self
+    fn read_line(&self) -> io::Result<String> {
+        let mut buf = String::new();
+        if io::stdin().read_line(&mut buf)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
+        Ok(buf)
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum LineOffset {
-    Relative(isize),
-    Absolute(usize),
-
-}
-impl Default for LineOffset {
-    fn default() -> Self {
-        Self::Relative(0)
-    }
+/// Input taken from a string, one line at a time.
+#[derive(Default)]
+pub struct StringInput {
+    lines: RefCell<VecDeque<String>>,
 }
 
-impl LineOffset {
-    pub fn shift(&self, l: usize) -> usize {
-        match self {
-            Self::Absolute(i) => *i,
-            Self::Relative(i) => {
-                let x = l as isize + i;
-                x.try_into().unwrap_or(1)
-            }
-        }
+impl StringInput {
+    pub fn new(text: &str) -> Self {
+        Self { lines: RefCell::new(text.split_inclusive('\n').map(String::from).collect()) }
     }
 }
 
-impl FromStr for LineOffset {
-    type Err = ();
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let first = s.get(0..1).ok_or(())?;
-        if first == "+" {
-            if s[1..].is_empty() {
-                Ok(Self::Relative(1))
-            } else {
-                s[1..].parse::<isize>()
-                    .map(Self::Relative)
-                    .map_err(|_| ())
+impl TextInput for StringInput {
+    fn read(&self) -> io::Result<String> {
+        let mut buf = String::new();
+        loop {
+            let line = self.read_line()?;
+            if line.trim_end() == "." {
+                return Ok(buf);
             }
-        } else if first == "-" {
-            if s[1..].is_empty() {
-                Ok(Self::Relative(-1))
-            } else {
-                s[1..].parse::<isize>()
-                    .map(|i| Self::Relative(-i))
-                    .map_err(|_| ())
+
+            buf.push_str(&line);
+            if !buf.ends_with('\n') {
+                buf.push('\n');
             }
-        } else {
-            s.parse::<usize>()
-                .map(Self::Absolute)
-                .map_err(|_| ())
         }
     }
+
+    fn read_line(&self) -> io::Result<String> {
+        self.lines.borrow_mut().pop_front().ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
 }
 
 pub trait EditorFn {
@@ -148,39 +340,160 @@ where F: Fn(&mut Editor, &mut CommandContext) -> Result<(), CommandError>
     }
 }
 
+/// Everything a command can fail with, by kind. ed itself only says `?`;
+/// the messages here are what `h` and `H` explain it with.
 #[derive(Debug)]
 pub enum CommandError {
-    IOError(std::io::Error),
-    Generic,
-    Read,
+    Io(IoError),
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+/// Reading and writing. Failures of the underlying I/O carry what was being
+/// read or written.
+#[derive(Debug)]
+pub enum IoError {
+    /// An I/O error on the named file.
+    File(String, io::Error),
+    /// Reading commands or the text for them.
+    Input(io::Error),
+    /// Printing the result of a command.
+    Output(io::Error),
+    /// Drawing the screen in visual mode.
+    Terminal(io::Error),
+    /// Starting the given shell command.
+    Shell(String, io::Error),
     NotTty,
+    NoFileName,
+    ReadOnly,
+    Unencodable(char),
+    NoPreviousCommand,
+    CommandFailed,
+}
+
+/// Parsing commands and addresses.
+#[derive(Debug)]
+pub enum ParseError {
+    UnknownCommand,
+    InvalidAddress,
+    AddressOutOfRange,
+    InvalidSuffix,
+    InvalidDestination,
+    InvalidPattern,
+    InvalidLineEnding,
+    InvalidRegister,
+    InvalidMark,
+    NestedGlobal,
+}
+
+/// Running commands.
+#[derive(Debug)]
+pub enum RuntimeError {
+    NoPreviousPattern,
+    NoPreviousReplacement,
+    NoMatch,
+    BufferModified,
+    NothingToUndo,
+    NothingToRedo,
+    NoSuchBuffer,
+    EmptyRegister,
 }
 
 impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Parse(_) | Self::Runtime(_) => None,
+        }
+    }
+}
+
+impl Error for IoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::File(_, e) | Self::Input(e) | Self::Output(e) | Self::Terminal(e) | Self::Shell(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for ParseError {}
+impl Error for RuntimeError {}
+
+impl From<IoError> for CommandError {
+    fn from(err: IoError) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ParseError> for CommandError {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(err)
+    }
 }
 
-impl From<std::io::Error> for CommandError {
-    fn from(err: std::io::Error) -> Self {
-        Self::IOError(err)
+impl From<RuntimeError> for CommandError {
+    fn from(err: RuntimeError) -> Self {
+        Self::Runtime(err)
     }
 }
 
 impl Display for CommandError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "command error")
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Parse(e) => e.fmt(f),
+            Self::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Display for IoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path, e) => write!(f, "{}: {}", path, e),
+            Self::Input(e) => write!(f, "cannot read input: {}", e),
+            Self::Output(e) => write!(f, "cannot write output: {}", e),
+            Self::Terminal(e) => write!(f, "cannot draw screen: {}", e),
+            Self::Shell(command, e) => write!(f, "{}: {}", command, e),
+            Self::NotTty => write!(f, "not a terminal"),
+            Self::NoFileName => write!(f, "no current file name"),
+            Self::ReadOnly => write!(f, "buffer is read-only"),
+            Self::Unencodable(c) => write!(f, "cannot encode {:?}", c),
+            Self::NoPreviousCommand => write!(f, "no previous command"),
+            Self::CommandFailed => write!(f, "command failed"),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownCommand => write!(f, "unknown command"),
+            Self::InvalidAddress => write!(f, "invalid address"),
+            Self::AddressOutOfRange => write!(f, "address out of range"),
+            Self::InvalidSuffix => write!(f, "invalid command suffix"),
+            Self::InvalidDestination => write!(f, "invalid destination"),
+            Self::InvalidPattern => write!(f, "invalid pattern"),
+            Self::InvalidLineEnding => write!(f, "unknown line ending"),
+            Self::InvalidRegister => write!(f, "invalid register"),
+            Self::InvalidMark => write!(f, "invalid mark character"),
+            Self::NestedGlobal => write!(f, "cannot nest global commands"),
+        }
+    }
+}
 
-    #[test]
-    fn test_parse_line_offset() {
-        assert_eq!("1".parse::<LineOffset>().unwrap(), LineOffset::Absolute(1));
-        assert_eq!("+".parse::<LineOffset>().unwrap(), LineOffset::Relative(1));
-        assert_eq!("+3".parse::<LineOffset>().unwrap(), LineOffset::Relative(3));
-        assert_eq!("-".parse::<LineOffset>().unwrap(), LineOffset::Relative(-1));
-        assert_eq!("-3".parse::<LineOffset>().unwrap(), LineOffset::Relative(-3));
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoPreviousPattern => write!(f, "no previous pattern"),
+            Self::NoPreviousReplacement => write!(f, "no previous substitution"),
+            Self::NoMatch => write!(f, "no match"),
+            Self::BufferModified => write!(f, "warning: buffer modified"),
+            Self::NothingToUndo => write!(f, "nothing to undo"),
+            Self::NothingToRedo => write!(f, "nothing to redo"),
+            Self::NoSuchBuffer => write!(f, "no such buffer"),
+            Self::EmptyRegister => write!(f, "register is empty"),
+        }
     }
 }
@@ -0,0 +1,129 @@
+use std::{borrow::Cow, fmt::Display, str::FromStr};
+
+/// First of 256 private-use code points that stand in for bytes which are
+/// not valid UTF-8, so they survive editing and are written back unchanged.
+const RAW_BASE: u32 = 0x10FF00;
+
+/// Character encoding of a file. Buffers hold decoded text and encode it
+/// again when writing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Latin1,
+}
+
+impl Encoding {
+    /// Picks UTF-8 for valid UTF-8 and for binary data, and Latin-1 for
+    /// other text. Binary data keeps its invalid bytes as raw characters.
+    pub fn detect(bytes: &[u8]) -> Self {
+        let binary = bytes.iter().any(|b| matches!(b, 0..=8 | 0x0e..=0x1a | 0x1c..=0x1f));
+        if std::str::from_utf8(bytes).is_ok() || binary {
+            Encoding::Utf8
+        } else {
+            Encoding::Latin1
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Cow<'_, str> {
+        match self {
+            Encoding::Utf8 => decode_utf8(bytes),
+            Encoding::Latin1 => Cow::Owned(bytes.iter().map(|b| char::from(*b)).collect()),
+        }
+    }
+
+    /// Encodes `s`, or returns the first character this encoding cannot
+    /// represent.
+    pub fn encode(self, s: &str) -> Result<Cow<'_, [u8]>, char> {
+        if !s.chars().any(|c| raw_byte(c).is_some()) && self == Encoding::Utf8 {
+            return Ok(Cow::Borrowed(s.as_bytes()));
+        }
+
+        let mut out = Vec::with_capacity(s.len());
+        for c in s.chars() {
+            match (raw_byte(c), self) {
+                (Some(b), _) => out.push(b),
+                (None, Encoding::Utf8) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                (None, Encoding::Latin1) => out.push(u8::try_from(c).map_err(|_| c)?),
+            }
+        }
+        Ok(Cow::Owned(out))
+    }
+}
+
+/// The original byte a raw character stands for.
+pub fn raw_byte(c: char) -> Option<u8> {
+    u32::from(c).checked_sub(RAW_BASE).and_then(|b| u8::try_from(b).ok())
+}
+
+fn decode_utf8(mut bytes: &[u8]) -> Cow<'_, str> {
+    let mut out = String::new();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(s) if out.is_empty() => return Cow::Borrowed(s),
+            Ok(s) => {
+                out.push_str(s);
+                return Cow::Owned(out);
+            },
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let invalid = e.error_len().unwrap_or(rest.len());
+                for b in &rest[..invalid] {
+                    out.push(char::from_u32(RAW_BASE + u32::from(*b)).expect("private-use code point"));
+                }
+                bytes = &rest[invalid..];
+            },
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "UTF-8"),
+            Encoding::Latin1 => write!(f, "ISO-8859-1"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" | "iso8859-1" => Ok(Encoding::Latin1),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Encoding::detect("héllo\n".as_bytes()), Encoding::Utf8);
+        assert_eq!(Encoding::detect(b"h\xe9llo\n"), Encoding::Latin1);
+        assert_eq!(Encoding::detect(b"\x00\x01\xff\xfe"), Encoding::Utf8);
+    }
+
+    #[test]
+    fn test_latin1_round_trip() {
+        let text = Encoding::Latin1.decode(b"caf\xe9\n");
+        assert_eq!(text, "café\n");
+        assert_eq!(Encoding::Latin1.encode(&text).unwrap().as_ref(), b"caf\xe9\n");
+        assert_eq!(Encoding::Latin1.encode("€"), Err('€'));
+    }
+
+    #[test]
+    fn test_invalid_bytes_round_trip() {
+        let bytes = b"ok \xff\xc3 \xc3\xa9\n";
+        let text = Encoding::Utf8.decode(bytes);
+        assert_eq!(text.chars().filter_map(raw_byte).collect::<Vec<_>>(), vec![0xff, 0xc3]);
+        assert!(text.ends_with(" é\n"));
+        assert_eq!(Encoding::Utf8.encode(&text).unwrap().as_ref(), bytes);
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+/// Marked lines by mark name.
+pub type Marks = HashMap<char, usize>;
+
+/// A single text replacement, kept with enough information to revert it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+/// All edits made by one command, along with the current line and whether
+/// the final newline was missing before and after it ran, and the marks
+/// before its first edit. Marks after it ran follow from redoing the edits.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    pub edits: Vec<Edit>,
+    pub line_before: usize,
+    pub line_after: usize,
+    pub missing_newline_before: bool,
+    pub missing_newline_after: bool,
+    pub marks_before: Marks,
+}
+
+#[derive(Debug, Default)]
+pub struct Journal {
+    undo: Vec<Transaction>,
+    redo: Vec<Transaction>,
+    pending: Option<Transaction>,
+}
+
+impl Journal {
+    pub fn begin(&mut self, line: usize) {
+        self.pending = Some(Transaction { line_before: line, ..Default::default() });
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Keeps a copy of `marks` and the missing newline flag to restore on
+    /// undo, if the open transaction has no edits yet. Later edits keep the
+    /// state saved before the first.
+    pub fn save_state(&mut self, marks: &Marks, missing_newline: bool) {
+        if let Some(t) = self.pending.as_mut().filter(|t| t.edits.is_empty()) {
+            t.marks_before = marks.clone();
+            t.missing_newline_before = missing_newline;
+        }
+    }
+
+    /// Records an edit in the open transaction.
+    pub fn record(&mut self, edit: Edit) {
+        if let Some(t) = &mut self.pending {
+            t.edits.push(edit);
+        }
+    }
+
+    pub fn commit(&mut self, line: usize, missing_newline: bool) {
+        if let Some(mut t) = self.pending.take() {
+            if !t.edits.is_empty() {
+                t.line_after = line;
+                t.missing_newline_after = missing_newline;
+                self.undo.push(t);
+                self.redo.clear();
+            }
+        }
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Transaction> {
+        self.undo.pop()
+    }
+
+    pub fn push_undo(&mut self, t: Transaction) {
+        self.undo.push(t);
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Transaction> {
+        self.redo.pop()
+    }
+
+    pub fn push_redo(&mut self, t: Transaction) {
+        self.redo.push(t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_groups_edits() {
+        let mut journal = Journal::default();
+
+        journal.begin(1);
+        journal.record(edit("a"));
+        journal.record(edit("b"));
+        journal.commit(2, false);
+
+        let t = journal.pop_undo().unwrap();
+        assert_eq!(t.edits, vec![edit("a"), edit("b")]);
+        assert_eq!((t.line_before, t.line_after), (1, 2));
+        assert!(journal.pop_undo().is_none());
+    }
+
+    #[test]
+    fn test_empty_transaction_is_dropped() {
+        let mut journal = Journal::default();
+        journal.push_redo(Transaction::default());
+
+        journal.begin(1);
+        journal.commit(1, false);
+
+        assert!(journal.pop_undo().is_none());
+        assert!(journal.pop_redo().is_some());
+    }
+
+    #[test]
+    fn test_new_change_clears_redo() {
+        let mut journal = Journal::default();
+        journal.push_redo(Transaction::default());
+
+        journal.begin(1);
+        journal.record(edit("a"));
+        journal.commit(1, false);
+
+        assert!(journal.pop_redo().is_none());
+        assert!(journal.pop_undo().is_some());
+    }
+
+    #[test]
+    fn test_state_saved_before_first_edit() {
+        let mut journal = Journal::default();
+
+        journal.begin(1);
+        journal.save_state(&Marks::from([('a', 1)]), true);
+        journal.record(edit("a"));
+        journal.save_state(&Marks::from([('a', 2)]), false);
+        journal.record(edit("b"));
+        journal.commit(1, false);
+
+        let t = journal.pop_undo().unwrap();
+        assert_eq!(t.marks_before, Marks::from([('a', 1)]));
+        assert!(t.missing_newline_before);
+        assert!(!t.missing_newline_after);
+    }
+
+    fn edit(s: &str) -> Edit {
+        Edit { start: 0, removed: String::new(), inserted: String::from(s) }
+    }
+}
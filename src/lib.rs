@@ -0,0 +1,35 @@
+//! A line editor in the style of POSIX `ed`.
+//!
+//! [`Session`] runs command strings against an [`Editor`] and returns what
+//! they printed:
+//!
+//! ```
+//! use rust_ed::Session;
+//!
+//! let mut session = Session::with_contents("hello\nworld\n");
+//! session.run("1s/hello/goodbye/").unwrap();
+//! assert_eq!(session.run(",p").unwrap().output, "goodbye\nworld\n");
+//! ```
+
+mod address;
+mod anchors;
+mod buffer;
+mod commands;
+mod editor;
+mod encoding;
+mod journal;
+mod line_ending;
+mod map;
+mod mapped;
+mod position;
+mod registers;
+mod rope;
+mod session;
+mod shell;
+mod substitute;
+
+pub use buffer::Buffer;
+pub use editor::{CommandError, Editor, IoError, ParseError, RuntimeError, StdTextInput, StringInput, TextInput};
+pub use encoding::Encoding;
+pub use session::{Outcome, Session};
+pub use shell::{Shell, ShellOutput, SystemShell};
@@ -0,0 +1,99 @@
+use std::{borrow::Cow, fmt::Display, str::FromStr};
+
+/// Line terminator style of a file. Buffers always hold `\n` internally and
+/// convert back to the file's style when writing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    /// Style of the first line break in `s`, or LF if there is none.
+    pub fn detect(s: &str) -> Self {
+        match s.find(['\n', '\r']) {
+            Some(i) if s[i..].starts_with("\r\n") => LineEnding::CrLf,
+            Some(i) if s[i..].starts_with('\r') => LineEnding::Cr,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    /// Converts line breaks of this style to `\n`.
+    pub fn normalize(self, s: &str) -> Cow<'_, str> {
+        match self {
+            LineEnding::Lf => Cow::Borrowed(s),
+            LineEnding::CrLf => Cow::Owned(s.replace("\r\n", "\n")),
+            LineEnding::Cr => Cow::Owned(s.replace('\r', "\n")),
+        }
+    }
+
+    /// Converts `\n` line breaks to this style.
+    pub fn apply(self, s: &str) -> Cow<'_, str> {
+        match self {
+            LineEnding::Lf => Cow::Borrowed(s),
+            _ => Cow::Owned(s.replace('\n', self.as_str())),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+impl Display for LineEnding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineEnding::Lf => write!(f, "LF"),
+            LineEnding::CrLf => write!(f, "CRLF"),
+            LineEnding::Cr => write!(f, "CR"),
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Ok(LineEnding::Lf),
+            "crlf" | "dos" => Ok(LineEnding::CrLf),
+            "cr" | "mac" => Ok(LineEnding::Cr),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(LineEnding::detect("a\nb\r\n"), LineEnding::Lf);
+        assert_eq!(LineEnding::detect("a\r\nb\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect("a\rb\r"), LineEnding::Cr);
+        assert_eq!(LineEnding::detect("no break"), LineEnding::Lf);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (style, text) in [(LineEnding::CrLf, "a\r\nb\r\n"), (LineEnding::Cr, "a\rb\r"), (LineEnding::Lf, "a\nb\n")] {
+            let normal = style.normalize(text);
+            assert_eq!(normal, "a\nb\n");
+            assert_eq!(style.apply(&normal), text);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("CRLF".parse(), Ok(LineEnding::CrLf));
+        assert_eq!("dos".parse(), Ok(LineEnding::CrLf));
+        assert_eq!("x".parse::<LineEnding>(), Err(()));
+    }
+}
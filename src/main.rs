@@ -1,114 +1,141 @@
-mod buffer;
-mod commands;
-mod editor;
-mod map;
+use std::{error::Error, fs, io::{self, Write}, process::ExitCode};
 
-use std::{error::Error, fmt::Display, fs::File, io::{self, Read, Write}};
-
-use buffer::Buffer;
 use crossterm::{cursor, style, terminal, tty::IsTty, ExecutableCommand as _};
-use editor::{CommandError, Editor, EditorMode};
-use commands as cmds;
-use map::CommandMap;
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let buffer = if let Some(path) = std::env::args().nth(1) {
-        let mut contents = String::new();
-        File::open(path)?.read_to_string(&mut contents)?;
-        Buffer::with_contents(&contents)
-    } else {
-        Buffer::default()
-    };
-
-    let mut editor = Editor { buffer, mode: EditorMode::Command };
-
-    let mut cmd_map = CommandMap::default();
-    cmd_map.bind("a", "append", cmds::append);
-    cmd_map.bind("c", "change", cmds::change_line);
-    cmd_map.bind("l", "list", cmds::list);
-    cmd_map.bind("p", "print-line", cmds::print_line);
-    cmd_map.bind("q", "quit", cmds::quit);
-    cmd_map.bind("d", "display", cmds::display);
-    cmd_map.bind_number("goto-line", cmds::goto_line);
+use rust_ed::{Buffer, CommandError, Editor, IoError, Outcome, Session, StdTextInput, StringInput, TextInput};
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
 
-    while editor.mode != EditorMode::Quit {
-        if let Err(e) = run_cycle(&mut editor, &cmd_map) {
-            println!("{:?}", e);
+fn run() -> Result<ExitCode, Box<dyn Error>> {
+    let mut read_only = false;
+    let mut encoding = None;
+    let mut silent = false;
+    let mut script = None;
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--read-only" => read_only = true,
+            "-s" => silent = true,
+            "--script" => script = Some(args.next().ok_or("--script needs a file")?),
+            "--encoding" => {
+                let name = args.next().ok_or("--encoding needs a value")?;
+                encoding = Some(name.parse().map_err(|_| format!("unknown encoding: {}", name))?);
+            },
+            _ => paths.push(arg),
         }
     }
 
-    Ok(())
-}
-
-fn run_cycle(editor: &mut Editor, cmd_map: &CommandMap) -> Result<(), Box<dyn Error>> {
-    match editor.mode {
-        EditorMode::Command => {
-            let cmd_str = read_command()?;
-            let cmd = cmd_map.lookup(&cmd_str).ok_or(CommandParseError::CommandNotFound)?;
+    // Commands and the text for `a`, `c` and `i` come from the same place.
+    // Anything but a terminal stops at the first error, as POSIX asks of
+    // non-interactive use.
+    let input: Box<dyn TextInput> = match &script {
+        Some(path) => Box::new(StringInput::new(&fs::read_to_string(path)?)),
+        None => Box::new(StdTextInput),
+    };
+    let interactive = script.is_none() && io::stdin().is_tty();
+
+    // Every file is opened the way `e` opens it, in a buffer of its own;
+    // the first one starts out current. A file that can't be read is
+    // reported, as GNU ed does, and left to be created by `w`.
+    let editor = Editor::default().silent(silent).encoding(encoding).read_only(read_only);
+    let mut session = Session::new(editor);
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            session.run_with("B", &mut io::stdout(), input.as_ref())?;
+        }
+        if let Err(e) = session.open_file(path, &mut io::stdout()) {
+            eprintln!("{}", e);
+        }
+    }
+    if paths.len() > 1 {
+        session.run_with("b 1", &mut io::stdout(), input.as_ref())?;
+    }
 
-            cmd.run(editor)?;
-            Ok(())
-        },
-        EditorMode::Visual => {
-            if !io::stdout().is_tty() {
-                return Err(Box::new(CommandError::NotTty));
-            }
-
-            let (_, size_y) = terminal::size()?;
-            let lines_n: usize = (size_y - 2).into();
-            io::stdout()
-                .execute(terminal::Clear(terminal::ClearType::All))?
-                .execute(cursor::MoveTo(0, 0))?;
-
-            for (n, line) in editor.buffer.lines_around(editor.buffer.line, lines_n) {
-                if n == editor.buffer.line {
-                    io::stdout().execute(style::SetAttribute(style::Attribute::Bold))?;
+    // Errors are reported with a bare `?`, explained by `h` or, after `H`,
+    // right away.
+    let mut quit = false;
+    while !quit {
+        match run_cycle(&mut session, input.as_ref(), silent) {
+            Ok(outcome) => quit = outcome.quit,
+            Err(e) => {
+                println!("?");
+                if session.editor.explains_errors() {
+                    println!("{}", e);
                 }
+                if !interactive {
+                    return Ok(ExitCode::FAILURE);
+                }
+            },
+        }
+    }
 
-                io::stdout()
-                    .execute(style::Print(n))?
-                    .execute(style::Print(' '))?
-                    .execute(style::Print(line))?
-                    .execute(style::SetAttribute(style::Attribute::Reset))?
-                    .execute(style::Print('\n'))?;
-            }
+    Ok(ExitCode::SUCCESS)
+}
 
-            io::stdout()
-                .execute(cursor::MoveTo(0, size_y))?
-                .execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
+fn run_cycle(session: &mut Session, input: &dyn TextInput, silent: bool) -> Result<Outcome, CommandError> {
+    if session.editor.is_visual() {
+        if !io::stdout().is_tty() {
+            return Err(CommandError::Io(IoError::NotTty));
+        }
+        draw_screen(session.buffer()).map_err(IoError::Terminal)?;
+    }
 
-            let cmd_str = read_command()?;
-            let cmd = cmd_map.lookup(&cmd_str).ok_or(CommandParseError::CommandNotFound)?;
+    let cmd_str = read_command(input, silent)?;
+    session.run_with(&cmd_str, &mut io::stdout(), input)
+}
 
-            cmd.run(editor)?;
+/// Shows the lines around the current one, with the prompt on the bottom
+/// row.
+fn draw_screen(buffer: &Buffer) -> io::Result<()> {
+    let (size_x, size_y) = terminal::size()?;
+    let lines_n: usize = (size_y - 2).into();
+    io::stdout()
+        .execute(terminal::Clear(terminal::ClearType::All))?
+        .execute(cursor::MoveTo(0, 0))?;
+
+    for (n, _) in buffer.lines_around(buffer.line, lines_n) {
+        if n == buffer.line {
+            io::stdout().execute(style::SetAttribute(style::Attribute::Bold))?;
+        }
 
-            Ok(())
-        },
-        EditorMode::Quit => Ok(()),
+        // Lines wider than the terminal are cut off and marked with a `>`
+        // in the last column.
+        let room = usize::from(size_x).saturating_sub(n.to_string().len() + 2);
+        let (visible, cut) = buffer.fit_line(n, room);
+
+        io::stdout()
+            .execute(style::Print(n))?
+            .execute(style::Print(' '))?
+            .execute(style::Print(visible))?
+            .execute(style::Print(if cut { ">" } else { "" }))?
+            .execute(style::SetAttribute(style::Attribute::Reset))?
+            .execute(style::Print('\n'))?;
     }
-}
-
-fn read_command() -> Result<String, Box<dyn Error>> {
-    let mut out = std::io::stdout();
-    write!(out, "> ")?;
-    out.flush()?;
-    let mut buf = String::new();
-    std::io::stdin().read_line(&mut buf)?;
-    Ok(buf)
-}
 
-#[derive(Debug)]
-enum CommandParseError {
-    CommandNotFound,
+    io::stdout()
+        .execute(cursor::MoveTo(0, size_y))?
+        .execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
+    Ok(())
 }
 
-impl Error for CommandParseError {
-}
+/// Reads the next command, prompting for it unless `silent`. The end of
+/// the input reads as `q`.
+fn read_command(input: &dyn TextInput, silent: bool) -> Result<String, CommandError> {
+    if !silent {
+        let mut out = io::stdout();
+        write!(out, "> ").and_then(|_| out.flush()).map_err(IoError::Output)?;
+    }
 
-impl Display for CommandParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::CommandNotFound => write!(f, "No such command"),
-        }
+    match input.read_line() {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(String::from("q")),
+        result => Ok(result.map_err(IoError::Input)?),
     }
 }
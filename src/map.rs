@@ -1,6 +1,10 @@
-use std::{collections::HashMap, fmt::{Debug, Formatter}};
+use std::{collections::HashMap, fmt::{Debug, Formatter}, io::Write};
 
-use crate::{commands::noop, editor::{CommandContext, CommandError, Editor, EditorFn, LineOffset}};
+use crate::{
+    address::{self, Range},
+    commands::{self as cmds, noop},
+    editor::{CommandContext, CommandError, Editor, EditorFn, ParseError, RuntimeError, TextInput},
+};
 
 pub struct CommandMap {
     map: HashMap<char, InnerCommand>,
@@ -26,12 +30,14 @@ struct InnerCommand {
 pub struct Command<'a> {
     f: &'a dyn EditorFn,
     name: &'a str,
-    line: LineOffset,
+    range: Range,
+    args: String,
+    map: &'a CommandMap,
 }
 
 impl Debug for Command<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "Command {{ name: {} line: {:?} }}", self.name, self.line)
+        write!(f, "Command {{ name: {} range: {:?} args: {:?} }}", self.name, self.range, self.args)
     }
 }
 
@@ -42,26 +48,111 @@ impl Default for InnerCommand {
 }
 
 impl Command<'_> {
-    pub fn run(&self, ed: &mut Editor) -> Result<(), CommandError> {
-        let mut out = std::io::stdout();
-        let mut ctx = CommandContext::with_output(&mut out).line_offset(&self.line);
+    /// Runs the command as a top-level one, writing to `output` and reading
+    /// text from `input`.
+    pub fn run_with(&self, ed: &mut Editor, output: &mut dyn Write, input: &dyn TextInput) -> Result<(), CommandError> {
+        let mut ctx = CommandContext {
+            range: self.range.clone(),
+            args: &self.args,
+            output,
+            input,
+            commands: Some(self.map),
+            nested: false,
+        };
+        self.run_in(ed, &mut ctx)
+    }
+
+    fn run_in(&self, ed: &mut Editor, ctx: &mut CommandContext) -> Result<(), CommandError> {
+        ed.buffer.begin_change();
+        let result = self.f.apply(ed, ctx);
+        ed.buffer.commit_change();
+        if !matches!(result, Err(CommandError::Runtime(RuntimeError::BufferModified))) {
+            ed.warned = false;
+        }
+        result
+    }
+
+    /// The same command applied to `range` instead of its own.
+    pub fn with_range(self, range: Range) -> Self {
+        Self { range, ..self }
+    }
+
+    /// Runs the command on behalf of another one, sharing its input, output
+    /// and command map.
+    pub fn run_nested(&self, ed: &mut Editor, parent: &mut CommandContext) -> Result<(), CommandError> {
+        let mut ctx = CommandContext {
+            range: self.range.clone(),
+            args: &self.args,
+            output: &mut *parent.output,
+            input: parent.input,
+            commands: parent.commands,
+            nested: true,
+        };
         self.f.apply(ed, &mut ctx)
     }
 }
 
 impl CommandMap {
-    pub fn lookup(&self, s: &str) -> Option<Command> {
-        let s = s.trim();
-        if s.is_empty() {
-            let cmd = &self.noop;
-            Some(Command { f: cmd.f.as_ref(), name: cmd.name.as_ref(), line: LineOffset::default() })
-        } else if let Ok(line) = s.parse::<LineOffset>() {
-            let cmd = &self.number;
-            Some(Command { f: cmd.f.as_ref(), name: cmd.name.as_ref(), line })
+    /// The full set of ed commands this crate provides.
+    pub fn standard() -> Self {
+        let mut map = Self::default();
+        map.bind("a", "append", cmds::append);
+        map.bind("c", "change", cmds::change_line);
+        map.bind("l", "list", cmds::list);
+        map.bind("p", "print-line", cmds::print_line);
+        map.bind("n", "number", cmds::number);
+        map.bind("=", "line-number", cmds::line_number);
+        map.bind("q", "quit", cmds::quit);
+        map.bind("Q", "quit-force", cmds::quit_force);
+        map.bind("s", "substitute", cmds::substitute);
+        map.bind("w", "write", cmds::write);
+        map.bind("W", "write-append", cmds::write_append);
+        map.bind("e", "edit", cmds::edit);
+        map.bind("E", "edit-force", cmds::edit_force);
+        map.bind("r", "read", cmds::read);
+        map.bind("f", "filename", cmds::filename);
+        map.bind("L", "line-ending", cmds::line_ending);
+        map.bind("u", "undo", cmds::undo);
+        map.bind("U", "redo", cmds::redo);
+        map.bind("g", "global", cmds::global);
+        map.bind("v", "global-inverse", cmds::global_inverse);
+        map.bind("G", "global-interactive", cmds::global_interactive);
+        map.bind("V", "global-inverse-interactive", cmds::global_inverse_interactive);
+        map.bind("d", "delete", cmds::delete);
+        map.bind("i", "insert", cmds::insert);
+        map.bind("j", "join", cmds::join);
+        map.bind("m", "move", cmds::move_lines);
+        map.bind("t", "transfer", cmds::transfer);
+        map.bind("D", "display", cmds::display);
+        map.bind("b", "buffers", cmds::buffers);
+        map.bind("B", "buffer-open", cmds::buffer_open);
+        map.bind("C", "buffer-close", cmds::buffer_close);
+        map.bind("T", "transfer-to-buffer", cmds::transfer_to_buffer);
+        map.bind("M", "move-to-buffer", cmds::move_to_buffer);
+        map.bind("k", "mark", cmds::mark);
+        map.bind("y", "yank", cmds::yank);
+        map.bind("x", "put", cmds::put);
+        map.bind("\"", "register", cmds::register);
+        map.bind("!", "shell", cmds::shell);
+        map.bind("h", "help", cmds::help);
+        map.bind("H", "help-mode", cmds::help_mode);
+        map.bind_number("goto-line", cmds::goto_line);
+        map
+    }
+
+    pub fn lookup(&self, s: &str) -> Result<Command<'_>, CommandError> {
+        let (range, rest) = address::parse_range(s.trim())?;
+        let mut chars = rest.chars();
+        let cmd = if let Some(c) = chars.next() {
+            self.map.get(&c).ok_or(ParseError::UnknownCommand)?
+        } else if range == Range::Implicit {
+            &self.noop
         } else {
-            let cmd = s.chars().next().and_then(|c| self.map.get(&c))?;
-            Some(Command { f: cmd.f.as_ref(), name: cmd.name.as_ref(), line: LineOffset::default() })
-        }
+            &self.number
+        };
+
+        let args = String::from(chars.as_str());
+        Ok(Command { f: cmd.f.as_ref(), name: cmd.name.as_ref(), range, args, map: self })
     }
 
     pub fn bind(&mut self, short: &str, name: &str, f: impl EditorFn + 'static) {
@@ -77,6 +168,8 @@ impl CommandMap {
 
 #[cfg(test)]
 mod tests {
+    use crate::address::Address;
+
     use super::*;
 
     #[test]
@@ -94,11 +187,43 @@ mod tests {
 
         let cmd = map.lookup("123").unwrap();
         assert_eq!(cmd.name, "goto-line");
-        assert_eq!(cmd.line, LineOffset::Absolute(123));
+        assert_eq!(cmd.range, Range::Single(Address::Line(123)));
 
         let cmd = map.lookup("+").unwrap();
         assert_eq!(cmd.name, "goto-line");
-        assert_eq!(cmd.line, LineOffset::Relative(1));
+        assert_eq!(cmd.range, Range::Single(Address::Offset(Box::new(Address::Current), 1)));
+    }
+
+    #[test]
+    fn test_range_command() {
+        let mut map = CommandMap::default();
+        map.bind("p", "print-line", noop);
+
+        let cmd = map.lookup("1,5p").unwrap();
+        assert_eq!(cmd.name, "print-line");
+        assert_eq!(cmd.range, Range::Pair(Address::Line(1), Address::Line(5)));
+
+        let cmd = map.lookup("%p").unwrap();
+        assert_eq!(cmd.range, Range::Pair(Address::Line(1), Address::Last));
+    }
+
+    #[test]
+    fn test_command_args() {
+        let mut map = CommandMap::default();
+        map.bind("s", "substitute", noop);
+
+        let cmd = map.lookup("1,$s/a/b/g").unwrap();
+        assert_eq!(cmd.name, "substitute");
+        assert_eq!(cmd.args, "/a/b/g");
+    }
+
+    #[test]
+    fn test_lookup_errors() {
+        let mut map = CommandMap::default();
+        map.bind("p", "print-line", noop);
+
+        assert!(matches!(map.lookup("1z"), Err(CommandError::Parse(ParseError::UnknownCommand))));
+        assert!(matches!(map.lookup("'Ap"), Err(CommandError::Parse(ParseError::InvalidAddress))));
     }
 
     #[test]
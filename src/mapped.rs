@@ -0,0 +1,222 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex},
+    thread,
+};
+
+use memmap2::Mmap;
+
+use crate::{encoding::Encoding, line_ending::LineEnding};
+
+const INDEX_BLOCK: usize = 1 << 20;
+const READ_BLOCK: usize = 1 << 16;
+
+/// Read-only text backed by a memory-mapped file. Line offsets are indexed
+/// by a background thread; lookups wait only until the index reaches the
+/// part of the file they need.
+///
+/// Offsets are into the file's bytes, but text is handed out decoded and,
+/// for CRLF files, with `\n` line breaks like an editable buffer holds.
+pub struct MappedText {
+    map: Arc<Mmap>,
+    index: Arc<LineIndex>,
+    line_ending: LineEnding,
+}
+
+#[derive(Default)]
+struct LineIndex {
+    state: Mutex<IndexState>,
+    progress: Condvar,
+    stop: AtomicBool,
+}
+
+#[derive(Default)]
+struct IndexState {
+    newlines: Vec<usize>,
+    scanned: usize,
+    done: bool,
+}
+
+impl MappedText {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only ever read. Like any mapped file, its
+        // contents may change if another process modifies the file.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        let index = Arc::new(LineIndex::default());
+        let line_ending = LineEnding::detect(&Encoding::Utf8.decode(&map[..map.len().min(1 << 16)]));
+
+        let (m, i) = (Arc::clone(&map), Arc::clone(&index));
+        thread::spawn(move || build_index(&m, &i));
+
+        Ok(Self { map, index, line_ending })
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn newlines(&self) -> usize {
+        self.wait_until(|s| s.done).newlines.len()
+    }
+
+    pub fn last_char(&self) -> Option<char> {
+        let tail = &self.map[self.len().saturating_sub(4)..];
+        Encoding::Utf8.decode(tail).chars().next_back()
+    }
+
+    pub fn newlines_before(&self, at: usize) -> usize {
+        self.wait_until(|s| s.scanned >= at)
+            .newlines
+            .partition_point(|p| *p < at)
+    }
+
+    pub fn nth_newline(&self, n: usize) -> Option<usize> {
+        self.wait_until(|s| s.newlines.len() > n).newlines.get(n).copied()
+    }
+
+    /// Text between two byte offsets. A `\r` ending the slice is left out
+    /// when it belongs to a CRLF line break.
+    pub fn slice(&self, start: usize, end: usize) -> Cow<'_, str> {
+        let mut bytes = &self.map[start.min(end)..end];
+        if self.line_ending == LineEnding::CrLf && self.map.get(end) == Some(&b'\n') {
+            bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        }
+        self.decode(bytes)
+    }
+
+    /// Start of the line containing `at`.
+    pub fn chunk_start(&self, at: usize) -> usize {
+        if at >= self.len() {
+            return self.len();
+        }
+
+        match self.newlines_before(at) {
+            0 => 0,
+            n => self.nth_newline(n - 1).map(|p| p + 1).unwrap_or(0),
+        }
+    }
+
+    /// Iterates over blocks of whole lines, starting with the line that
+    /// contains `at`. Blocks are borrowed from the mapping unless they hold
+    /// invalid UTF-8, which is decoded like `slice` does.
+    pub fn chunks_from(&self, at: usize) -> impl Iterator<Item = Cow<'_, str>> {
+        let mut pos = self.chunk_start(at);
+        std::iter::from_fn(move || {
+            let rest = &self.map[pos..];
+            if rest.is_empty() {
+                return None;
+            }
+
+            let end = match memchr::memchr(b'\n', &rest[READ_BLOCK.min(rest.len() - 1)..]) {
+                Some(n) => READ_BLOCK.min(rest.len() - 1) + n + 1,
+                None => rest.len(),
+            };
+            pos += end;
+            Some(self.decode(&rest[..end]))
+        })
+    }
+
+    fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match (Encoding::Utf8.decode(bytes), self.line_ending) {
+            (text, LineEnding::CrLf) if text.contains('\r') => Cow::Owned(text.replace("\r\n", "\n")),
+            (text, _) => text,
+        }
+    }
+
+    fn wait_until(&self, ready: impl Fn(&IndexState) -> bool) -> std::sync::MutexGuard<'_, IndexState> {
+        let state = self.index.state.lock().unwrap();
+        self.index.progress.wait_while(state, |s| !s.done && !ready(s)).unwrap()
+    }
+}
+
+impl Drop for MappedText {
+    fn drop(&mut self) {
+        self.index.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn build_index(map: &Mmap, index: &LineIndex) {
+    for (n, block) in map.chunks(INDEX_BLOCK).enumerate() {
+        if index.stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let offset = n * INDEX_BLOCK;
+        let newlines = memchr::memchr_iter(b'\n', block).map(|p| p + offset);
+
+        let mut state = index.state.lock().unwrap();
+        state.newlines.extend(newlines);
+        state.scanned = offset + block.len();
+        index.progress.notify_all();
+    }
+
+    index.state.lock().unwrap().done = true;
+    index.progress.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapped_lines() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-mapped", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let text = MappedText::open(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(text.len(), 14);
+        assert_eq!(text.newlines(), 3);
+        assert_eq!(text.nth_newline(1), Some(7));
+        assert_eq!(text.nth_newline(3), None);
+        assert_eq!(text.newlines_before(8), 2);
+        assert_eq!(text.chunk_start(9), 8);
+        assert_eq!(text.slice(4, 7), "two");
+        assert_eq!(text.last_char(), Some('\n'));
+        assert_eq!(text.chunks_from(5).collect::<String>(), "two\nthree\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-mapped-invalid", std::process::id()));
+        std::fs::write(&path, b"a\xffb\nsecond\n").unwrap();
+        let text = MappedText::open(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(text.chunks_from(0).collect::<String>(), "a\u{10ffff}b\nsecond\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_crlf() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-mapped-crlf", std::process::id()));
+        std::fs::write(&path, "one\r\ntwo\r\n").unwrap();
+        let text = MappedText::open(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(text.line_ending(), LineEnding::CrLf);
+        assert_eq!(text.slice(0, 3), "one");
+        assert_eq!(text.slice(0, 4), "one");
+        assert_eq!(text.slice(0, 10), "one\ntwo\n");
+        assert_eq!(text.chunks_from(0).collect::<String>(), "one\ntwo\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-mapped-empty", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let text = MappedText::open(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(text.len(), 0);
+        assert_eq!(text.newlines(), 0);
+        assert_eq!(text.last_char(), None);
+        assert_eq!(text.chunks_from(0).count(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
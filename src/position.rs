@@ -0,0 +1,115 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const TAB_WIDTH: usize = 8;
+
+/// What a column number counts.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColumnUnit {
+    Bytes,
+    #[default]
+    Graphemes,
+    /// Terminal cells, with tabs advancing to the next multiple of eight.
+    Width,
+}
+
+/// A line and column in a buffer. Lines count from 1 like addresses do,
+/// columns count from 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column }
+    }
+}
+
+/// Column of byte offset `at` in `line`. Offsets inside a character or
+/// grapheme cluster count as the start of it.
+pub fn column_at(line: &str, at: usize, unit: ColumnUnit) -> usize {
+    let at = floor_boundary(line, at);
+    match unit {
+        ColumnUnit::Bytes => at,
+        ColumnUnit::Graphemes => line.grapheme_indices(true).filter(|(i, g)| i + g.len() <= at).count(),
+        ColumnUnit::Width => cells(line)
+            .find(|cell| at < cell.end)
+            .map(|cell| cell.column)
+            .unwrap_or_else(|| cells(line).map(|cell| cell.width).sum()),
+    }
+}
+
+/// Byte offset where `column` starts in `line`. Columns past the end give
+/// the line length; columns inside a character or a wide cell give its
+/// start.
+pub fn offset_at(line: &str, column: usize, unit: ColumnUnit) -> usize {
+    let found = match unit {
+        ColumnUnit::Bytes => Some(floor_boundary(line, column)),
+        ColumnUnit::Graphemes => line.grapheme_indices(true).nth(column).map(|(i, _)| i),
+        ColumnUnit::Width => cells(line).find(|cell| cell.column + cell.width > column).map(|cell| cell.start),
+    };
+    found.unwrap_or(line.len())
+}
+
+/// A grapheme cluster as laid out on a terminal.
+struct Cell {
+    start: usize,
+    end: usize,
+    column: usize,
+    width: usize,
+}
+
+fn cells(line: &str) -> impl Iterator<Item = Cell> + '_ {
+    let mut column = 0;
+    line.grapheme_indices(true).map(move |(start, g)| {
+        let width = if g == "\t" { TAB_WIDTH - column % TAB_WIDTH } else { g.width() };
+        let cell = Cell { start, end: start + g.len(), column, width };
+        column += width;
+        cell
+    })
+}
+
+fn floor_boundary(s: &str, at: usize) -> usize {
+    let mut at = at.min(s.len());
+    while !s.is_char_boundary(at) {
+        at -= 1;
+    }
+    at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "e" with a combining acute accent, then a wide character.
+    const LINE: &str = "ae\u{301}中b";
+
+    #[test]
+    fn test_column_at() {
+        assert_eq!(column_at(LINE, 4, ColumnUnit::Bytes), 4);
+        assert_eq!(column_at(LINE, 4, ColumnUnit::Graphemes), 2);
+        assert_eq!(column_at(LINE, 4, ColumnUnit::Width), 2);
+        assert_eq!(column_at(LINE, 7, ColumnUnit::Width), 4);
+        assert_eq!(column_at(LINE, 5, ColumnUnit::Bytes), 4);
+        assert_eq!(column_at(LINE, 3, ColumnUnit::Graphemes), 1);
+        assert_eq!(column_at(LINE, 8, ColumnUnit::Width), 5);
+    }
+
+    #[test]
+    fn test_offset_at() {
+        assert_eq!(offset_at(LINE, 2, ColumnUnit::Bytes), 2);
+        assert_eq!(offset_at(LINE, 5, ColumnUnit::Bytes), 4);
+        assert_eq!(offset_at(LINE, 2, ColumnUnit::Graphemes), 4);
+        assert_eq!(offset_at(LINE, 3, ColumnUnit::Width), 4);
+        assert_eq!(offset_at(LINE, 4, ColumnUnit::Width), 7);
+        assert_eq!(offset_at(LINE, 10, ColumnUnit::Graphemes), LINE.len());
+    }
+
+    #[test]
+    fn test_tab_width() {
+        assert_eq!(column_at("ab\tc", 3, ColumnUnit::Width), 8);
+        assert_eq!(offset_at("ab\tc", 5, ColumnUnit::Width), 2);
+    }
+}
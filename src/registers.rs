@@ -0,0 +1,102 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::editor::{CommandError, ParseError};
+
+/// Number of deletions kept in the kill ring, reachable as registers `1`
+/// to `9`.
+const KILL_RING_SIZE: usize = 9;
+
+/// Text saved by yanks and deletions. The unnamed register holds whatever
+/// was saved last; `a` to `z` are only written when selected, with `A` to
+/// `Z` appending to them.
+#[derive(Debug, Default)]
+pub struct Registers {
+    unnamed: Option<String>,
+    named: HashMap<char, String>,
+    kills: VecDeque<String>,
+    /// Register chosen with `"x` for the command being run.
+    pub selected: Option<char>,
+}
+
+impl Registers {
+    pub fn check_name(name: char) -> Result<(), CommandError> {
+        if name.is_ascii_alphanumeric() && name != '0' {
+            Ok(())
+        } else {
+            Err(CommandError::Parse(ParseError::InvalidRegister))
+        }
+    }
+
+    /// Contents of register `name`, or of the unnamed register.
+    pub fn get(&self, name: Option<char>) -> Option<&str> {
+        match name {
+            None => self.unnamed.as_deref(),
+            Some(c @ '1'..='9') => self.kills.get(c as usize - '1' as usize).map(String::as_str),
+            Some(c) => self.named.get(&c.to_ascii_lowercase()).map(String::as_str),
+        }
+    }
+
+    /// Saves `text` in register `name`, and the register's new contents in
+    /// the unnamed register. Numbered registers are read-only.
+    pub fn set(&mut self, name: Option<char>, text: &str) -> Result<(), CommandError> {
+        let contents = match name {
+            None => String::from(text),
+            Some('1'..='9') => return Err(CommandError::Parse(ParseError::InvalidRegister)),
+            Some(c) if c.is_ascii_uppercase() => {
+                let register = self.named.entry(c.to_ascii_lowercase()).or_default();
+                register.push_str(text);
+                register.clone()
+            },
+            Some(c) => {
+                self.named.insert(c, String::from(text));
+                String::from(text)
+            },
+        };
+
+        self.unnamed = Some(contents);
+        Ok(())
+    }
+
+    /// Saves deleted text like `set`, and pushes it onto the kill ring.
+    pub fn kill(&mut self, name: Option<char>, text: &str) -> Result<(), CommandError> {
+        self.set(name, text)?;
+        self.kills.push_front(String::from(text));
+        self.kills.truncate(KILL_RING_SIZE);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_registers() {
+        let mut registers = Registers::default();
+
+        registers.set(Some('a'), "one\n").unwrap();
+        registers.set(Some('A'), "two\n").unwrap();
+        registers.set(Some('b'), "three\n").unwrap();
+
+        assert_eq!(registers.get(Some('a')), Some("one\ntwo\n"));
+        assert_eq!(registers.get(Some('A')), Some("one\ntwo\n"));
+        assert_eq!(registers.get(None), Some("three\n"));
+        registers.set(Some('A'), "four\n").unwrap();
+        assert_eq!(registers.get(None), Some("one\ntwo\nfour\n"));
+        assert_eq!(registers.get(Some('c')), None);
+        assert!(matches!(registers.set(Some('1'), ""), Err(CommandError::Parse(ParseError::InvalidRegister))));
+    }
+
+    #[test]
+    fn test_kill_ring() {
+        let mut registers = Registers::default();
+
+        for n in 0..12 {
+            registers.kill(None, &n.to_string()).unwrap();
+        }
+
+        assert_eq!(registers.get(Some('1')), Some("11"));
+        assert_eq!(registers.get(Some('9')), Some("3"));
+        assert_eq!(registers.get(None), Some("11"));
+    }
+}
@@ -0,0 +1,449 @@
+use std::borrow::Cow;
+
+const CHUNK_SIZE: usize = 2048;
+
+type Tree = Option<Box<Node>>;
+
+/// Text stored as a balanced tree (a treap) of chunks. Chunks always end on
+/// a line boundary, so every line is contiguous in memory. Each node keeps
+/// the byte and newline counts of its subtree, and a sorted index of the
+/// line starts within its own chunk, so line lookups never scan text.
+pub struct Rope {
+    root: Tree,
+    seed: u64,
+    chunk_size: usize,
+}
+
+struct Node {
+    text: String,
+    line_starts: Vec<usize>,
+    priority: u64,
+    total_bytes: usize,
+    total_newlines: usize,
+    left: Tree,
+    right: Tree,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self { root: None, seed: 0x9e3779b97f4a7c15, chunk_size: CHUNK_SIZE }
+    }
+}
+
+impl Rope {
+    pub fn with_text(s: &str) -> Self {
+        let mut rope = Self::default();
+        rope.replace(0, 0, s);
+        rope
+    }
+
+    #[cfg(test)]
+    fn with_chunk_size(s: &str, chunk_size: usize) -> Self {
+        let mut rope = Self { chunk_size, ..Default::default() };
+        rope.replace(0, 0, s);
+        rope
+    }
+
+    pub fn len(&self) -> usize {
+        total_bytes(&self.root)
+    }
+
+    pub fn newlines(&self) -> usize {
+        total_newlines(&self.root)
+    }
+
+    pub fn last_char(&self) -> Option<char> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+
+        let (start, text) = find_chunk(&self.root, len - 1)?;
+        text[..len - start].chars().next_back()
+    }
+
+    /// Number of newlines before the byte offset `at`.
+    pub fn newlines_before(&self, at: usize) -> usize {
+        newlines_before(&self.root, at)
+    }
+
+    /// Byte offset of the `n`th newline, counting from zero.
+    pub fn nth_newline(&self, n: usize) -> Option<usize> {
+        nth_newline(&self.root, n)
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> Cow<'_, str> {
+        if start >= end {
+            return Cow::Borrowed("");
+        }
+
+        let Some((chunk_start, text)) = find_chunk(&self.root, start) else {
+            return Cow::Borrowed("");
+        };
+
+        if end <= chunk_start + text.len() {
+            return Cow::Borrowed(&text[start - chunk_start..end - chunk_start]);
+        }
+
+        let mut out = String::with_capacity(end - start);
+        let mut pos = chunk_start;
+        for chunk in self.chunks_from(start) {
+            let from = start.saturating_sub(pos);
+            let to = (end - pos).min(chunk.len());
+            out.push_str(&chunk[from..to]);
+            pos += chunk.len();
+            if pos >= end {
+                break;
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    /// Iterates over chunks, starting with the one containing `at`.
+    pub fn chunks_from(&self, at: usize) -> Chunks<'_> {
+        let mut stack = Vec::new();
+        let mut node = self.root.as_deref();
+        let mut at = at;
+        while let Some(n) = node {
+            let left = total_bytes(&n.left);
+            if at < left {
+                stack.push(n);
+                node = n.left.as_deref();
+            } else if at < left + n.text.len() {
+                stack.push(n);
+                break;
+            } else {
+                at -= left + n.text.len();
+                node = n.right.as_deref();
+            }
+        }
+
+        Chunks { stack, first: true }
+    }
+
+    pub fn chunk_start(&self, at: usize) -> usize {
+        find_chunk(&self.root, at).map(|(start, _)| start).unwrap_or(self.len())
+    }
+
+    /// Replaces the bytes between `start` and `end` with `s`. Only the chunks
+    /// touched by the edit are rebuilt.
+    pub fn replace(&mut self, start: usize, end: usize, s: &str) {
+        let len = self.len();
+        let from = self.chunk_start(start.min(len.saturating_sub(1)));
+        let to = match find_chunk(&self.root, end) {
+            Some((chunk_start, _)) if chunk_start == end => end,
+            Some((chunk_start, text)) => chunk_start + text.len(),
+            None => len,
+        };
+
+        let (left, rest) = split(self.root.take(), from);
+        let (middle, mut right) = split(rest, to - from);
+
+        let mut text = String::with_capacity(to - from + s.len());
+        collect(&middle, &mut text);
+        text.replace_range(start - from..end - from, s);
+
+        while !text.is_empty() && !text.ends_with('\n') && right.is_some() {
+            let first = find_chunk(&right, 0).map(|(_, t)| t.len()).unwrap_or(0);
+            let (chunk, rest) = split(right, first);
+            collect(&chunk, &mut text);
+            right = rest;
+        }
+
+        let mut middle = None;
+        for chunk in split_chunks(&text, self.chunk_size) {
+            let node = self.node(chunk);
+            middle = merge(middle, node);
+        }
+
+        self.root = merge(merge(left, middle), right);
+    }
+
+    fn node(&mut self, text: &str) -> Tree {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+
+        let line_starts = text.match_indices('\n').map(|(n, _)| n + 1).collect::<Vec<_>>();
+        Some(Box::new(Node {
+            text: String::from(text),
+            total_newlines: line_starts.len(),
+            line_starts,
+            priority: self.seed,
+            total_bytes: text.len(),
+            left: None,
+            right: None,
+        }))
+    }
+}
+
+pub struct Chunks<'a> {
+    stack: Vec<&'a Node>,
+    first: bool,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.first {
+            return self.advance();
+        }
+
+        self.first = false;
+        self.stack.last().map(|n| n.text.as_str()).or_else(|| self.advance())
+    }
+}
+
+impl<'a> Chunks<'a> {
+    fn advance(&mut self) -> Option<&'a str> {
+        let node = self.stack.pop()?;
+        let mut next = node.right.as_deref();
+        while let Some(n) = next {
+            self.stack.push(n);
+            next = n.left.as_deref();
+        }
+
+        self.stack.last().map(|n| n.text.as_str())
+    }
+}
+
+/// Cuts `text` into chunks of at least `size` bytes that end after a
+/// newline. Only the last chunk may end without one.
+fn split_chunks(text: &str, size: usize) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let cut = rest.as_bytes()
+            .iter()
+            .skip(size.min(rest.len()) - 1)
+            .position(|b| *b == b'\n')
+            .map(|n| n + size.min(rest.len()))
+            .unwrap_or(rest.len());
+
+        let (chunk, tail) = rest.split_at(cut);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+fn total_bytes(t: &Tree) -> usize {
+    t.as_ref().map(|n| n.total_bytes).unwrap_or(0)
+}
+
+fn total_newlines(t: &Tree) -> usize {
+    t.as_ref().map(|n| n.total_newlines).unwrap_or(0)
+}
+
+fn update(n: &mut Node) {
+    n.total_bytes = total_bytes(&n.left) + n.text.len() + total_bytes(&n.right);
+    n.total_newlines = total_newlines(&n.left) + n.line_starts.len() + total_newlines(&n.right);
+}
+
+fn merge(a: Tree, b: Tree) -> Tree {
+    match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority > b.priority {
+                a.right = merge(a.right.take(), Some(b));
+                update(&mut a);
+                Some(a)
+            } else {
+                b.left = merge(Some(a), b.left.take());
+                update(&mut b);
+                Some(b)
+            }
+        },
+    }
+}
+
+/// Splits the tree so that the left part holds the chunks starting before
+/// byte `at`. `at` must fall on a chunk boundary.
+fn split(t: Tree, at: usize) -> (Tree, Tree) {
+    let Some(mut n) = t else {
+        return (None, None);
+    };
+
+    let left = total_bytes(&n.left);
+    if at <= left {
+        let (l, r) = split(n.left.take(), at);
+        n.left = r;
+        update(&mut n);
+        (l, Some(n))
+    } else {
+        let (l, r) = split(n.right.take(), at.saturating_sub(left + n.text.len()));
+        n.right = l;
+        update(&mut n);
+        (Some(n), r)
+    }
+}
+
+fn collect(t: &Tree, out: &mut String) {
+    if let Some(n) = t {
+        collect(&n.left, out);
+        out.push_str(&n.text);
+        collect(&n.right, out);
+    }
+}
+
+fn find_chunk(t: &Tree, at: usize) -> Option<(usize, &str)> {
+    let mut node = t.as_deref();
+    let mut offset = 0;
+    while let Some(n) = node {
+        let left = total_bytes(&n.left);
+        if at < offset + left {
+            node = n.left.as_deref();
+        } else if at < offset + left + n.text.len() {
+            return Some((offset + left, &n.text));
+        } else {
+            offset += left + n.text.len();
+            node = n.right.as_deref();
+        }
+    }
+
+    None
+}
+
+fn newlines_before(t: &Tree, at: usize) -> usize {
+    let mut node = t.as_deref();
+    let mut at = at;
+    let mut count = 0;
+    while let Some(n) = node {
+        let left = total_bytes(&n.left);
+        if at <= left {
+            node = n.left.as_deref();
+        } else if at <= left + n.text.len() {
+            return count + total_newlines(&n.left) + n.line_starts.partition_point(|p| *p <= at - left);
+        } else {
+            count += total_newlines(&n.left) + n.line_starts.len();
+            at -= left + n.text.len();
+            node = n.right.as_deref();
+        }
+    }
+
+    count
+}
+
+fn nth_newline(t: &Tree, k: usize) -> Option<usize> {
+    let mut node = t.as_deref();
+    let mut k = k;
+    let mut offset = 0;
+    while let Some(n) = node {
+        let left = total_newlines(&n.left);
+        if k < left {
+            node = n.left.as_deref();
+        } else if k < left + n.line_starts.len() {
+            return Some(offset + total_bytes(&n.left) + n.line_starts[k - left] - 1);
+        } else {
+            k -= left + n.line_starts.len();
+            offset += total_bytes(&n.left) + n.text.len();
+            node = n.right.as_deref();
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_chunks() {
+        let chunks = split_chunks("ab\ncd\nef\ng", 2).collect::<Vec<_>>();
+        assert_eq!(chunks, vec!["ab\n", "cd\n", "ef\n", "g"]);
+
+        let chunks = split_chunks("a\nb\nc\n", 3).collect::<Vec<_>>();
+        assert_eq!(chunks, vec!["a\nb\n", "c\n"]);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut rope = Rope::with_chunk_size("one\ntwo\nthree\n", 2);
+        assert_eq!(text(&rope), "one\ntwo\nthree\n");
+
+        rope.replace(4, 7, "TWO");
+        assert_eq!(text(&rope), "one\nTWO\nthree\n");
+
+        rope.replace(3, 4, " ");
+        assert_eq!(text(&rope), "one TWO\nthree\n");
+
+        rope.replace(0, rope.len(), "");
+        assert_eq!(rope.len(), 0);
+
+        rope.replace(0, 0, "x");
+        rope.replace(1, 1, "y\n");
+        assert_eq!(text(&rope), "xy\n");
+    }
+
+    #[test]
+    fn test_chunks_stay_on_line_boundaries() {
+        let mut rope = Rope::with_chunk_size("aa\nbb\ncc\ndd\n", 2);
+        rope.replace(5, 6, "");
+        rope.replace(2, 3, "");
+
+        let chunks = rope.chunks_from(0).collect::<Vec<_>>();
+        assert_eq!(chunks.concat(), "aabbcc\ndd\n");
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.ends_with('\n')));
+    }
+
+    #[test]
+    fn test_line_index() {
+        let text: String = (1..=100).map(|i| format!("{i}\n")).collect();
+        let rope = Rope::with_chunk_size(&text, 8);
+
+        assert_eq!(rope.newlines(), 100);
+        assert_eq!(rope.nth_newline(0), Some(1));
+        assert_eq!(rope.nth_newline(9), Some(20));
+        assert_eq!(rope.nth_newline(100), None);
+        assert_eq!(rope.newlines_before(0), 0);
+        assert_eq!(rope.newlines_before(21), 10);
+        assert_eq!(rope.newlines_before(rope.len()), 100);
+        assert_eq!(rope.last_char(), Some('\n'));
+    }
+
+    #[test]
+    fn test_slice_across_chunks() {
+        let rope = Rope::with_chunk_size("one\ntwo\nthree\n", 2);
+
+        assert_eq!(rope.slice(1, 3), "ne");
+        assert_eq!(rope.slice(2, 10), "e\ntwo\nth");
+        assert!(matches!(rope.slice(4, 7), Cow::Borrowed("two")));
+        assert_eq!(rope.chunks_from(9).next(), Some("three\n"));
+    }
+
+    #[test]
+    fn test_random_edits_match_string() {
+        let mut rope = Rope::with_chunk_size("", 4);
+        let mut expected = String::new();
+        let mut seed: usize = 12345;
+        let mut next = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n.max(1)
+        };
+
+        for i in 0..500 {
+            let start = next(expected.len() + 1);
+            let end = start + next(expected.len() - start + 1).min(6);
+            let s = ["", "x", "\n", "ab\n", "line\nmore\n"][next(5)];
+            rope.replace(start, end, s);
+            expected.replace_range(start..end, s);
+
+            assert_eq!(text(&rope), expected, "after edit {i}");
+            assert_eq!(rope.newlines(), expected.matches('\n').count());
+
+            let at = next(expected.len() + 1);
+            assert_eq!(rope.newlines_before(at), expected[..at].matches('\n').count());
+            let k = next(rope.newlines() + 1);
+            assert_eq!(rope.nth_newline(k), expected.match_indices('\n').nth(k).map(|(n, _)| n));
+        }
+    }
+
+    fn text(rope: &Rope) -> String {
+        rope.slice(0, rope.len()).into_owned()
+    }
+}
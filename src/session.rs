@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use crate::{
+    buffer::Buffer,
+    editor::{CommandError, Editor, EditorMode, StringInput, TextInput},
+    map::CommandMap,
+};
+
+/// An editor together with the standard command set, for running commands
+/// without a terminal.
+pub struct Session {
+    pub editor: Editor,
+    commands: CommandMap,
+}
+
+/// What a command printed and the state it left the editor in.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub output: String,
+    pub line: usize,
+    pub modified: bool,
+    pub quit: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(Editor::default())
+    }
+}
+
+impl Session {
+    pub fn new(editor: Editor) -> Self {
+        Self { editor, commands: CommandMap::standard() }
+    }
+
+    pub fn with_contents(text: &str) -> Self {
+        Self::new(Editor::with_buffer(Buffer::with_contents(text), None))
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.editor.buffer
+    }
+
+    pub fn run(&mut self, command: &str) -> Result<Outcome, CommandError> {
+        self.run_with_input(command, "")
+    }
+
+    /// Runs `command`, taking any text it reads (for `a`, `i`, `c` and the
+    /// command lists of `g`) from `input`.
+    pub fn run_with_input(&mut self, command: &str, input: &str) -> Result<Outcome, CommandError> {
+        let mut output = Vec::new();
+        self.run_with(command, &mut output, &StringInput::new(input))?;
+        Ok(Outcome { output: String::from_utf8_lossy(&output).into_owned(), ..self.outcome() })
+    }
+
+    /// Runs `command`, writing what it prints straight to `output` and
+    /// reading text from `input` as it is needed. The outcome's `output` is
+    /// left empty. Errors are remembered here, and only here, for `h`.
+    pub fn run_with(&mut self, command: &str, output: &mut dyn Write, input: &dyn TextInput) -> Result<Outcome, CommandError> {
+        let result = self.commands.lookup(command).and_then(|cmd| cmd.run_with(&mut self.editor, output, input));
+        if let Err(e) = &result {
+            self.editor.record_error(e);
+        }
+        result.map(|_| self.outcome())
+    }
+
+    /// Reads `path` into the current buffer the way `e` does. The buffer
+    /// is named after the file even if it can't be read, so that `w`
+    /// creates it, as when a new file is given on the command line.
+    pub fn open_file(&mut self, path: &str, output: &mut dyn Write) -> Result<Outcome, CommandError> {
+        let input = StringInput::default();
+        if !path.starts_with('!') {
+            self.run_with(&format!("f {}", path), &mut io::sink(), &input)?;
+        }
+        self.run_with(&format!("e {}", path), output, &input)
+    }
+
+    fn outcome(&self) -> Outcome {
+        Outcome {
+            output: String::new(),
+            line: self.editor.buffer.line,
+            modified: self.editor.buffer.modified,
+            quit: self.editor.mode == EditorMode::Quit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+    use crate::editor::{IoError, ParseError, RuntimeError};
+
+    #[test]
+    fn test_run() {
+        let mut session = Session::with_contents("one\ntwo\n");
+
+        let outcome = session.run("1p").unwrap();
+        assert_eq!(outcome, Outcome { output: String::from("one\n"), line: 1, modified: false, quit: false });
+
+        let outcome = session.run_with_input("a", "new\n.\n").unwrap();
+        assert!(outcome.modified);
+        assert_eq!(session.buffer().text(), "one\nnew\ntwo\n");
+
+        assert!(matches!(session.run("q"), Err(CommandError::Runtime(RuntimeError::BufferModified))));
+        assert!(session.run("q").unwrap().quit);
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let mut session = Session::default();
+
+        assert!(matches!(session.run("1~"), Err(CommandError::Parse(ParseError::UnknownCommand))));
+        assert_eq!(session.run("h").unwrap().output, "unknown command\n");
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        let path = std::env::temp_dir().join(format!("rust-ed-{}-test_open_missing_file", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut session = Session::default();
+
+        let result = session.open_file(&path, &mut io::sink());
+        assert!(matches!(result, Err(CommandError::Io(IoError::File(_, ref e))) if e.kind() == io::ErrorKind::NotFound));
+        assert_eq!(session.editor.filename.as_deref(), Some(path.as_str()));
+
+        session.run_with_input("a", "new\n.\n").unwrap();
+        assert_eq!(session.run("w").unwrap().output, "4\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_error_source() {
+        let mut session = Session::default();
+
+        let e = session.run("r /nonexistent/file").unwrap_err();
+        let file_error = e.source().unwrap();
+        assert_eq!(file_error.to_string(), "/nonexistent/file: No such file or directory (os error 2)");
+        let io_error = file_error.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_error.kind(), io::ErrorKind::NotFound);
+        assert!(io_error.source().is_none());
+    }
+}
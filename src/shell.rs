@@ -0,0 +1,103 @@
+use std::{io::{self, Write}, process::{Command, Stdio}, thread};
+
+use crate::editor::{CommandError, IoError};
+
+/// What a shell command wrote to its standard output, and whether it
+/// exited successfully.
+#[derive(Debug, Default)]
+pub struct ShellOutput {
+    pub stdout: Vec<u8>,
+    pub success: bool,
+}
+
+/// Runs the shell commands given to `!`, `r !`, `w !` and `e !`. Tests
+/// swap in a stub so that nothing is actually spawned.
+pub trait Shell {
+    /// Runs `command`, feeding it `input` if given. Without input the
+    /// command shares the editor's standard input.
+    fn run(&self, command: &str, input: Option<&[u8]>) -> io::Result<ShellOutput>;
+}
+
+/// Runs commands with `sh -c`.
+#[derive(Debug, Default)]
+pub struct SystemShell;
+
+impl Shell for SystemShell {
+    fn run(&self, command: &str, input: Option<&[u8]>) -> io::Result<ShellOutput> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::inherit() })
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        // Input is written from another thread so that a command producing
+        // output before it has read everything cannot block on a full pipe.
+        let stdin = child.stdin.take();
+        let output = thread::scope(|s| {
+            if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                s.spawn(move || match stdin.write_all(input) {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+                    _ => Ok(()),
+                });
+            }
+            child.wait_with_output()
+        })?;
+
+        Ok(ShellOutput { stdout: output.stdout, success: output.status.success() })
+    }
+}
+
+/// Expands a command given to `!`: a leading `!` stands for the previous
+/// command and `%` for the file name, with `\%` giving a literal `%`.
+/// Returns the expanded command and whether anything was replaced.
+pub fn expand(command: &str, previous: Option<&str>, filename: Option<&str>) -> Result<(String, bool), CommandError> {
+    let mut out = String::new();
+    let mut expanded = false;
+    let mut rest = command;
+    if let Some(after) = command.strip_prefix('!') {
+        out.push_str(previous.ok_or(IoError::NoPreviousCommand)?);
+        expanded = true;
+        rest = after;
+    }
+
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.as_str().starts_with('%') => {
+                out.push('%');
+                chars.next();
+            },
+            '%' => {
+                out.push_str(filename.ok_or(IoError::NoFileName)?);
+                expanded = true;
+            },
+            c => out.push(c),
+        }
+    }
+
+    Ok((out, expanded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("ls", None, None).unwrap(), (String::from("ls"), false));
+        assert_eq!(expand("wc %", None, Some("a.rs")).unwrap(), (String::from("wc a.rs"), true));
+        assert_eq!(expand("echo \\%", None, None).unwrap(), (String::from("echo %"), false));
+        assert_eq!(expand("! -l", Some("ls"), None).unwrap(), (String::from("ls -l"), true));
+        assert!(matches!(expand("!", None, None), Err(CommandError::Io(IoError::NoPreviousCommand))));
+        assert!(matches!(expand("cat %", None, None), Err(CommandError::Io(IoError::NoFileName))));
+    }
+
+    #[test]
+    fn test_system_shell() {
+        let output = SystemShell.run("tr a-z A-Z", Some(b"abc\n")).unwrap();
+        assert_eq!(output.stdout, b"ABC\n");
+        assert!(output.success);
+        assert!(!SystemShell.run("exit 3", Some(b"")).unwrap().success);
+    }
+}
@@ -0,0 +1,232 @@
+use regex::{Captures, Regex};
+
+use crate::{address::split_delimited, editor::{CommandError, Editor, ParseError, RuntimeError}};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Occurrence {
+    Nth(usize),
+    From(usize),
+}
+
+/// How a command prints the current line once it is done, from the `p`,
+/// `n` or `l` following it. Later variants are the more detailed formats.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum PrintSuffix {
+    #[default]
+    None,
+    Print,
+    Number,
+    List,
+}
+
+impl PrintSuffix {
+    /// Parses the suffix letters following a command, as in `dp` or `.,$n`.
+    /// Given several, the most detailed format wins.
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        s.trim_end().chars().try_fold(Self::None, |suffix, c| {
+            Self::from_letter(c).map(|s| suffix.max(s)).ok_or(CommandError::Parse(ParseError::InvalidSuffix))
+        })
+    }
+
+    pub fn from_letter(c: char) -> Option<Self> {
+        match c {
+            'p' => Some(Self::Print),
+            'n' => Some(Self::Number),
+            'l' => Some(Self::List),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed `s/re/replacement/flags` command.
+#[derive(Debug)]
+pub struct Substitution {
+    pub re: Regex,
+    pub replacement: String,
+    pub occurrence: Occurrence,
+    pub print: PrintSuffix,
+}
+
+impl Substitution {
+    /// Parses the text following the `s` command letter. An empty pattern
+    /// reuses the last search, and `~` (or a replacement consisting only of
+    /// `%`) stands for the previous replacement.
+    pub fn parse(ed: &mut Editor, args: &str) -> Result<Self, CommandError> {
+        let delim = args.chars().next()
+            .filter(|c| !c.is_whitespace() && *c != '\\')
+            .ok_or(ParseError::InvalidSuffix)?;
+
+        let (pattern, rest) = split_delimited(&args[delim.len_utf8()..], delim);
+        let (replacement, flags) = split_delimited(rest, delim);
+
+        let re = ed.search_pattern(&pattern)?;
+        let replacement = expand_previous(&replacement, ed.last_replacement.as_deref())?;
+        ed.last_replacement = Some(replacement.clone());
+
+        let mut global = false;
+        let mut nth = None;
+        let mut print = PrintSuffix::None;
+        let mut flags = flags.trim_end();
+        while let Some(c) = flags.chars().next() {
+            match c {
+                'g' => global = true,
+                '1'..='9' => {
+                    let end = flags.find(|c: char| !c.is_ascii_digit()).unwrap_or(flags.len());
+                    nth = Some(flags[..end].parse().map_err(|_| ParseError::InvalidSuffix)?);
+                    flags = &flags[end..];
+                    continue;
+                },
+                c => print = print.max(PrintSuffix::from_letter(c).ok_or(ParseError::InvalidSuffix)?),
+            }
+            flags = &flags[c.len_utf8()..];
+        }
+
+        let occurrence = match (global, nth) {
+            (true, n) => Occurrence::From(n.unwrap_or(1)),
+            (false, n) => Occurrence::Nth(n.unwrap_or(1)),
+        };
+
+        Ok(Self { re, replacement, occurrence, print })
+    }
+
+    /// Returns the substituted line, or `None` if nothing was replaced.
+    pub fn apply(&self, line: &str) -> Option<String> {
+        let mut out = String::new();
+        let mut last = 0;
+        let mut replaced = false;
+
+        for (i, caps) in self.re.captures_iter(line).enumerate() {
+            let n = i + 1;
+            let selected = match self.occurrence {
+                Occurrence::Nth(k) => n == k,
+                Occurrence::From(k) => n >= k,
+            };
+
+            if selected {
+                let m = caps.get(0).unwrap();
+                out.push_str(&line[last..m.start()]);
+                out.push_str(&expand(&self.replacement, &caps));
+                last = m.end();
+                replaced = true;
+            }
+        }
+
+        if replaced {
+            out.push_str(&line[last..]);
+            Some(out)
+        } else {
+            None
+        }
+    }
+}
+
+fn expand_previous(replacement: &str, previous: Option<&str>) -> Result<String, CommandError> {
+    if replacement == "%" {
+        return previous.map(String::from).ok_or(CommandError::Runtime(RuntimeError::NoPreviousReplacement));
+    }
+
+    let mut out = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                out.push(c);
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            },
+            '~' => out.push_str(previous.ok_or(RuntimeError::NoPreviousReplacement)?),
+            _ => out.push(c),
+        }
+    }
+
+    Ok(out)
+}
+
+fn expand(replacement: &str, caps: &Captures) -> String {
+    let mut out = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => out.push_str(&caps[0]),
+            '\\' => match chars.next() {
+                Some(d @ '1'..='9') => {
+                    let group = d.to_digit(10).unwrap() as usize;
+                    out.push_str(caps.get(group).map(|m| m.as_str()).unwrap_or(""));
+                },
+                Some(other) => out.push(other),
+                None => out.push(c),
+            },
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        let mut ed = Editor::default();
+
+        let s = Substitution::parse(&mut ed, "/a/b/").unwrap();
+        assert_eq!(s.occurrence, Occurrence::Nth(1));
+        assert_eq!(s.print, PrintSuffix::None);
+
+        let s = Substitution::parse(&mut ed, "/a/b/gp").unwrap();
+        assert_eq!(s.occurrence, Occurrence::From(1));
+        assert_eq!(s.print, PrintSuffix::Print);
+
+        let s = Substitution::parse(&mut ed, "/a/b/3n").unwrap();
+        assert_eq!(s.occurrence, Occurrence::Nth(3));
+        assert_eq!(s.print, PrintSuffix::Number);
+
+        assert!(matches!(Substitution::parse(&mut ed, "/a/b/x"), Err(CommandError::Parse(ParseError::InvalidSuffix))));
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(apply("/o/0/", "foo boo"), Some(String::from("f0o boo")));
+        assert_eq!(apply("/o/0/g", "foo boo"), Some(String::from("f00 b00")));
+        assert_eq!(apply("/o/0/3", "foo boo"), Some(String::from("foo b0o")));
+        assert_eq!(apply("/o/0/3g", "foo boo"), Some(String::from("foo b00")));
+        assert_eq!(apply("/x/y/", "foo"), None);
+    }
+
+    #[test]
+    fn test_replacement_expansion() {
+        assert_eq!(apply("/o+/[&]/", "foo"), Some(String::from("f[oo]")));
+        assert_eq!(apply("/o+/\\&/", "foo"), Some(String::from("f&")));
+        assert_eq!(apply("/(\\w+) (\\w+)/\\2 \\1/", "hello world"), Some(String::from("world hello")));
+        assert_eq!(apply("|a/b|c|", "a/b"), Some(String::from("c")));
+    }
+
+    #[test]
+    fn test_previous_pattern_and_replacement() {
+        let mut ed = Editor::default();
+
+        Substitution::parse(&mut ed, "/foo/bar/").unwrap();
+        let s = Substitution::parse(&mut ed, "//~~/").unwrap();
+        assert_eq!(s.re.as_str(), "foo");
+        assert_eq!(s.apply("foo"), Some(String::from("barbar")));
+
+        let s = Substitution::parse(&mut ed, "/x/%/").unwrap();
+        assert_eq!(s.replacement, "barbar");
+    }
+
+    #[test]
+    fn test_no_previous() {
+        let mut ed = Editor::default();
+
+        assert!(matches!(Substitution::parse(&mut ed, "//a/"), Err(CommandError::Runtime(RuntimeError::NoPreviousPattern))));
+        assert!(matches!(Substitution::parse(&mut ed, "/a/~/"), Err(CommandError::Runtime(RuntimeError::NoPreviousReplacement))));
+    }
+
+    fn apply(args: &str, line: &str) -> Option<String> {
+        let mut ed = Editor::default();
+        Substitution::parse(&mut ed, args).unwrap().apply(line)
+    }
+}